@@ -1,47 +1,154 @@
+use crate::description::DescriptionBuilder;
 #[cfg(feature = "http")]
 use crate::httpclient::HttpClient;
-use crate::opamp::{spec::*, util::*, Channel};
+use crate::opamp::capabilities::{AgentFlags, Capabilities};
+use crate::opamp::{spec::*, util::*, Channel, InstanceUid};
+use crate::state::DisconnectReason;
+#[cfg(any(feature = "http", feature = "websocket"))]
+use crate::state::{ConnectionStatus, StateTransition};
 #[cfg(feature = "websocket")]
 use crate::wsclient::WsClient;
-use std::{error::Error, fmt};
+use std::{error::Error, fmt, path::Path, time::Duration};
+#[cfg(any(feature = "http", feature = "websocket"))]
+use tokio::sync::broadcast;
 
 /// `pub trait ApiCallbacks` is defining a trait that must be implemented by OpAMP clients. It
 /// defines a set of methods that an implementing type must provide, which will be called by the `Api`
 /// struct during its operation. This allows for customization and extension of the behavior of the
 /// `Api` struct without modifying its core implementation.
 pub trait ApiCallbacks {
-    /// Request the client to report its current configuration
+    /// Request the client to report its current configuration. Called for the initial state and
+    /// again after a remote config was applied, unless the reply to it carried `effective_config`
     fn get_configuration(&mut self) -> Result<Option<AgentConfigMap>, ApiClientError>;
-    /// Asks the client to report a tuple of (capabilities, flags) for OpAMP
-    fn get_features(&mut self) -> (u64, u64);
+    /// Asks the client to report a tuple of (capabilities, flags) for OpAMP. Server messages
+    /// that need a capability which is not declared here are rejected without reaching the
+    /// callbacks.
+    fn get_features(&mut self) -> (Capabilities, AgentFlags);
     /// Primary execution loop of the OpAMP client
     fn on_loop(&mut self) -> Result<Option<AgentToServer>, ApiClientError>;
     /// Reverse reported errors
     fn on_error(&mut self, inbound: &ServerToAgent);
-    /// Health check callback for the supervisor to report its (and subagent) health
+    /// Health check callback for the supervisor to report its (and subagent) health. Receives
+    /// every message addressed to an instance_uid that is neither ours nor a registered child's
     fn on_health_check(
         &mut self,
         inbound: &ServerToAgent,
     ) -> Result<Option<AgentToServer>, ApiClientError>;
+    /// Callback for commands sent by the server. Not called for restarts once a restart task
+    /// was set with `Api::set_restart_task`
     fn on_command(
         &mut self,
         inbound: &ServerToAgent,
     ) -> Result<Option<AgentToServer>, ApiClientError>;
-    /// Callback that is invoked when the OpAMP server deploys a new config to this node
+    /// Callback that is invoked when the OpAMP server deploys a new config to this node, once
+    /// the config was reported as applying. The library reports the config as applied when this
+    /// returns `Ok` and as failed, with the error as its message, when it returns `Err`. A config whose hash was already applied is
+    /// not delivered again. A `remote_config_status` in the reply takes precedence.
     fn on_agent_remote_config(
         &mut self,
         inbound: &ServerToAgent,
     ) -> Result<Option<AgentToServer>, ApiClientError>;
-    /// Callback for suggesting/altering different connection parameters to the supervisor
+    /// Callback for suggesting/altering different connection parameters to the supervisor. An
+    /// `opamp` offer has already been validated and the client reconnects with it, going back to
+    /// the previous endpoint if the new one does not work within a minute.
     fn on_connection_settings_offers(
         &mut self,
         inbound: &ServerToAgent,
     ) -> Result<Option<AgentToServer>, ApiClientError>;
-    /// Reports on packages that are available for the supervisor to download and deploy
+    /// Reports on packages that are available for the supervisor to download and deploy. Not
+    /// called when a package manager was set with `Api::set_package_manager`
     fn on_packages_available(
         &mut self,
         inbound: &ServerToAgent,
     ) -> Result<Option<AgentToServer>, ApiClientError>;
+    /// Invoked when the transport connects to the server for the first time
+    fn on_connect(&mut self, _reason: &str) {}
+    /// Invoked when an established connection is lost or connecting has failed
+    /// `OPAMP_CONNECT_RETRIES` times in a row
+    fn on_disconnect(&mut self, _reason: &DisconnectReason) {}
+    /// Invoked when the transport connects again after a disconnect
+    fn on_reconnect(&mut self, _reason: &str) {}
+    /// Invoked when connection settings or certificates offered by the server are rejected, or
+    /// when offered settings did not connect and the previous ones were restored
+    fn on_connection_settings_rejected(&mut self, _error: &ApiClientError) {}
+    /// Invoked when the file written for offered `other_connections` changed and no restart task
+    /// was set to reload the agent. The agent should read `path` again
+    fn on_other_connections_changed(&mut self, _path: &Path) {}
+    /// Invoked when the server assigns this agent a new identity. All subsequent messages carry
+    /// `current`. Applications that keep their identity across restarts should persist it.
+    fn on_instance_uid_changed(&mut self, _previous: &InstanceUid, _current: &InstanceUid) {}
+    /// Invoked for every error response from the server, after `on_error`, once the library has
+    /// acted on it: sending pauses while the server is unavailable. Over HTTP the rejected
+    /// request is dropped on a bad request and sent again once the server is available. Over
+    /// WebSocket the error can not be matched to a message and nothing is sent again
+    fn on_server_error(&mut self, _error: &ServerError) {}
+    /// Asks the client for the components available in the managed agent, unless an inventory
+    /// was set with `Api::set_available_components`. Only used with the
+    /// `ReportsAvailableComponents` capability. The hash is computed when left empty.
+    fn get_available_components(&mut self) -> Option<AvailableComponents> {
+        None
+    }
+    /// Asks the client for the `AgentDescription` of the initial state. `default` already holds
+    /// the `service.*` attributes from `ConnectionSettings` and the built-in detectors; add
+    /// attributes or detectors to it before building. `service.instance.id` should be left as is.
+    fn get_description(&mut self, default: DescriptionBuilder) -> AgentDescription {
+        default.build()
+    }
+}
+
+/// `ChildCallbacks` is implemented by supervisors for each child agent they register with
+/// `Api::register_child`. It receives the server messages addressed to the child's
+/// `instance_uid`. Replies are sent on behalf of the child.
+pub trait ChildCallbacks {
+    /// Callback that is invoked when the OpAMP server deploys a new config to the child. The
+    /// config is reported as applied on `Ok` and as failed on `Err`. A `remote_config_status` in
+    /// the reply takes precedence.
+    fn on_agent_remote_config(
+        &mut self,
+        inbound: &ServerToAgent,
+    ) -> Result<Option<AgentToServer>, ApiClientError>;
+    /// Callback for commands sent to the child
+    fn on_command(
+        &mut self,
+        inbound: &ServerToAgent,
+    ) -> Result<Option<AgentToServer>, ApiClientError>;
+    /// Reports on packages that are available for the child to download and deploy
+    fn on_packages_available(
+        &mut self,
+        inbound: &ServerToAgent,
+    ) -> Result<Option<AgentToServer>, ApiClientError>;
+    /// Invoked when the server assigns the child a new identity
+    fn on_instance_uid_changed(&mut self, _previous: &InstanceUid, _current: &InstanceUid) {}
+}
+
+/// `CustomMessageHandler` receives the custom messages of one custom capability, registered with
+/// `Api::register_custom_capability`.
+pub trait CustomMessageHandler {
+    /// Invoked for each custom message the server sends for the capability. A returned message
+    /// is sent back to the server, unless another custom message is still waiting to be sent.
+    fn on_custom_message(
+        &mut self,
+        message: &CustomMessage,
+    ) -> Result<Option<CustomMessage>, ApiClientError>;
+}
+
+/// The `ChildAgent` struct describes a child agent managed by a supervisor over the supervisor's
+/// OpAMP connection.
+///
+/// Properties:
+///
+/// * `instance_uid`: Identity of the child, distinct from the supervisor's.
+/// * `capabilities`: Capabilities the child advertises. Messages for the child that need an
+///   undeclared capability are rejected.
+/// * `description`: The `AgentDescription` reported for the child.
+/// * `effective_config`: The configuration the child currently runs with.
+/// * `handler`: Receives the server messages addressed to the child.
+pub struct ChildAgent {
+    pub instance_uid: InstanceUid,
+    pub capabilities: Capabilities,
+    pub description: AgentDescription,
+    pub effective_config: Option<AgentConfigMap>,
+    pub handler: Box<dyn ChildCallbacks + Send + Sync>,
 }
 
 /// The above code defines a struct called ConnectionSettings with several fields for server connection
@@ -50,24 +157,32 @@ pub trait ApiCallbacks {
 /// Properties:
 ///
 /// * `server_endpoint`: The URL or IP address of the server that the connection will be established
-/// with.
+///   with.
 /// * `api_key`: The `api_key` property is a string that represents an authentication key used to access
-/// a server or API. It is typically used to identify and authorize a user or application to access
-/// specific resources or perform certain actions.
+///   a server or API. It is typically used to identify and authorize a user or application to access
+///   specific resources or perform certain actions.
 /// * `listen_path`: The `listen_path` property is a string that represents the path where the server
-/// will listen for incoming requests. This is typically a URL path that is used to route requests to
-/// the appropriate endpoint.
+///   will listen for incoming requests. This is typically a URL path that is used to route requests to
+///   the appropriate endpoint.
 /// * `name`: The name property is a string that represents the name of the connection. It
-/// could be used to identify the specific connection settings object or to provide a name for the
-/// connection settings that is meaningful to the user.
+///   could be used to identify the specific connection settings object or to provide a name for the
+///   connection settings that is meaningful to the user.
 /// * `version`: The `version` property is a string that represents the version of the application or
-/// service that is using these connection settings. It can be used to identify which version of the
-/// application is running when troubleshooting or debugging issues.
-/// * `instance_id`: The `instance_id` property is a ULID for the instance of the
-/// application or service that is using these connection settings. It can be used to differentiate
-/// between multiple instances running on the same or different machines or environments.
+///   service that is using these connection settings. It can be used to identify which version of the
+///   application is running when troubleshooting or debugging issues.
+/// * `instance_id`: The `instance_id` property is a ULID (or UUID) for the instance of the
+///   application or service that is using these connection settings. It can be used to differentiate
+///   between multiple instances running on the same or different machines or environments. It is
+///   converted to an `InstanceUid` and sent to the server as 16 bytes.
 /// * `debugmode`: `debugmode` is a property of type `log::LevelFilter` which is used to specify the
-/// level of logging that should be enabled for the connection.
+///   level of logging that should be enabled for the connection.
+/// * `key_store`: Optional directory where client certificates offered by the server are saved
+///   (readable by the owner only). A saved OpAMP client certificate is used again on startup.
+/// * `state_dir`: Optional directory where the agent keeps its state across restarts. The
+///   instance id, including one assigned by the server, is saved there and takes precedence over
+///   `instance_id` on the next start, and the last reported remote config status, effective config,
+///   package statuses and sequence number are restored from it. The directory is locked while the
+///   client runs.
 pub struct ConnectionSettings {
     pub server_endpoint: String,
     pub api_key: String,
@@ -76,6 +191,8 @@ pub struct ConnectionSettings {
     pub version: String,
     pub instance_id: String,
     pub debugmode: log::LevelFilter,
+    pub key_store: Option<std::path::PathBuf>,
+    pub state_dir: Option<std::path::PathBuf>,
 }
 
 #[derive(Debug)]
@@ -84,11 +201,11 @@ pub struct ConnectionSettings {
 /// Properties:
 ///
 /// * `code`: The `code` property is a public unsigned 32-bit integer that represents an error code
-/// associated with an `ApiClientError` instance. This code can be used to identify the specific type of
-/// error that occurred.
+///   associated with an `ApiClientError` instance. This code can be used to identify the specific type of
+///   error that occurred.
 /// * `details`: The `details` property is a `String` that provides additional information about the
-/// error that occurred in an `ApiClient`. It can be used to provide more context to the error code and
-/// help with debugging.
+///   error that occurred in an `ApiClient`. It can be used to provide more context to the error code and
+///   help with debugging.
 pub struct ApiClientError {
     pub code: u32,
     pub details: String,
@@ -119,6 +236,57 @@ impl Error for ApiClientError {
     }
 }
 
+/// The `ServerError` enum is the typed form of a `ServerErrorResponse` sent by the server
+#[derive(Clone, Debug, PartialEq)]
+pub enum ServerError {
+    /// The server could not process the message. Over HTTP it is dropped rather than sent again
+    BadRequest(String),
+    /// The server is temporarily unable to process messages. `retry_after` is the delay the
+    /// server asked for, if any. Without one the client backs off exponentially
+    Unavailable {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    /// Any other error reported by the server
+    Unknown(String),
+}
+
+impl From<&ServerErrorResponse> for ServerError {
+    fn from(response: &ServerErrorResponse) -> ServerError {
+        let message = response.error_message.clone();
+        match ServerErrorResponseType::from_i32(response.r#type) {
+            Some(ServerErrorResponseType::BadRequest) => ServerError::BadRequest(message),
+            Some(ServerErrorResponseType::Unavailable) => {
+                let retry_after = match &response.details {
+                    Some(server_error_response::Details::RetryInfo(info))
+                        if info.retry_after_nanoseconds > 0 =>
+                    {
+                        Some(Duration::from_nanos(info.retry_after_nanoseconds))
+                    }
+                    _ => None,
+                };
+                ServerError::Unavailable {
+                    message,
+                    retry_after,
+                }
+            }
+            _ => ServerError::Unknown(message),
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::BadRequest(message) => write!(f, "Bad request: {}", message),
+            ServerError::Unavailable { message, .. } => {
+                write!(f, "Server unavailable: {}", message)
+            }
+            ServerError::Unknown(message) => write!(f, "Server error: {}", message),
+        }
+    }
+}
+
 impl Default for ConnectionSettings {
     fn default() -> ConnectionSettings {
         ConnectionSettings {
@@ -129,53 +297,90 @@ impl Default for ConnectionSettings {
             version: std::env::var("CARGO_PKG_VERSION").unwrap_or("0.0.1".to_string()),
             instance_id: generate_ulid().to_string(),
             debugmode: log::LevelFilter::Info,
+            key_store: None,
+            state_dir: None,
         }
     }
 }
 
 pub struct Api<'a> {
-    pub client: Box<dyn Channel + 'a>,
+    pub client: Box<dyn Channel<'a> + 'a>,
 }
 
 impl Api<'_> {
     #[cfg(feature = "websocket")]
-    pub fn websocket_client(
+    fn try_websocket_client(
         settings: ConnectionSettings,
         cb: Box<dyn ApiCallbacks + Send + Sync + '_>,
-    ) -> Api {
-        Api {
-            client: Box::new(WsClient::new(settings, cb)),
-        }
+    ) -> Result<Api, ApiClientError> {
+        Ok(Api {
+            client: Box::new(WsClient::new(settings, cb)?),
+        })
     }
 
     #[cfg(feature = "http")]
-    pub fn http_client(
+    fn try_http_client(
         settings: ConnectionSettings,
         cb: Box<dyn ApiCallbacks + Send + Sync + '_>,
-    ) -> Api {
-        Api {
-            client: Box::new(HttpClient::new(settings, cb)),
-        }
+    ) -> Result<Api, ApiClientError> {
+        Ok(Api {
+            client: Box::new(HttpClient::new(settings, cb)?),
+        })
     }
 
     #[cfg(not(feature = "http"))]
-    pub fn http_client(_: ConnectionSettings, _: Box<dyn ApiCallbacks + Send + Sync + '_>) -> Api {
-        unimplemented!("Requires http feature")
+    fn try_http_client(
+        _: ConnectionSettings,
+        _: Box<dyn ApiCallbacks + Send + Sync + '_>,
+    ) -> Result<Api, ApiClientError> {
+        Err(ApiClientError::new(line!(), "Requires http feature"))
     }
 
     #[cfg(not(feature = "websocket"))]
-    pub fn websocket_client(
+    fn try_websocket_client(
         _: ConnectionSettings,
         _: Box<dyn ApiCallbacks + Send + Sync + '_>,
+    ) -> Result<Api, ApiClientError> {
+        Err(ApiClientError::new(line!(), "Requires websocket feature"))
+    }
+
+    /// Creates a websocket backed API handle.
+    ///
+    /// Panics if the websocket feature is disabled or the endpoint is not a valid URL.
+    pub fn websocket_client(
+        settings: ConnectionSettings,
+        cb: Box<dyn ApiCallbacks + Send + Sync + '_>,
+    ) -> Api {
+        Self::try_websocket_client(settings, cb).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Creates a HTTP backed API handle.
+    ///
+    /// Panics if the http feature is disabled or the endpoint is not a valid URL.
+    pub fn http_client(
+        settings: ConnectionSettings,
+        cb: Box<dyn ApiCallbacks + Send + Sync + '_>,
     ) -> Api {
-        unimplemented!("Requires websocket feature")
+        Self::try_http_client(settings, cb).unwrap_or_else(|e| panic!("{}", e))
     }
 
+    /// Creates an API handle, picking the transport from the scheme of `server_endpoint`.
+    ///
+    /// Panics on invalid settings. Prefer [`Api::try_new`] in long running processes.
     pub fn new(settings: ConnectionSettings, cb: Box<dyn ApiCallbacks + Send + Sync + '_>) -> Api {
+        Self::try_new(settings, cb).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Fallible variant of [`Api::new`]. Reports an error instead of panicking when the endpoint
+    /// cannot be parsed or the transport for its scheme was not compiled in.
+    pub fn try_new(
+        settings: ConnectionSettings,
+        cb: Box<dyn ApiCallbacks + Send + Sync + '_>,
+    ) -> Result<Api, ApiClientError> {
         if settings.server_endpoint.starts_with("http") {
-            Self::http_client(settings, cb)
+            Self::try_http_client(settings, cb)
         } else {
-            Self::websocket_client(settings, cb)
+            Self::try_websocket_client(settings, cb)
         }
     }
 
@@ -183,3 +388,275 @@ impl Api<'_> {
         self.client.trigger().await;
     }
 }
+
+#[cfg(any(feature = "http", feature = "websocket"))]
+impl Api<'_> {
+    /// Reports the current FSM state, the last error, the time of the last successful exchange,
+    /// the current sequence number and the connection retry count
+    pub fn status(&self) -> ConnectionStatus {
+        self.client.session().status()
+    }
+
+    /// Replaces the effective config reported to the server, e.g. after an operator edited local
+    /// configuration files. The new config is sent with the next exchange.
+    ///
+    /// Callbacks can achieve the same by returning a message carrying `effective_config`.
+    pub fn set_effective_config(
+        &mut self,
+        config_map: Option<AgentConfigMap>,
+    ) -> Result<(), ApiClientError> {
+        self.client.session_mut().set_effective_config(config_map)
+    }
+
+    /// Reports the health of the supervisor: whether it is healthy, a status string, the last
+    /// error and when it started. The start time and the components are kept unless `health`
+    /// sets them, and `status_time_unix_nano` defaults to now.
+    ///
+    /// The library does not infer health from the connection. Until this is called the agent is
+    /// reported as not healthy.
+    pub fn set_health(&mut self, health: ComponentHealth) -> Result<(), ApiClientError> {
+        self.client.session_mut().set_health(health)
+    }
+
+    /// Reports the health of a process managed by the supervisor under `name` in the
+    /// `component_health_map` of the supervisor's health. `None` removes the component.
+    pub fn set_component_health(
+        &mut self,
+        name: &str,
+        health: Option<ComponentHealth>,
+    ) -> Result<(), ApiClientError> {
+        self.client.session_mut().set_component_health(name, health)
+    }
+
+    /// Registers a child agent. Its full status is sent with its own `instance_uid`, and server
+    /// messages addressed to it are routed to its handler instead of these callbacks.
+    pub fn register_child(&mut self, child: ChildAgent) -> Result<(), ApiClientError> {
+        self.client.session_mut().register_child(child)
+    }
+
+    /// Removes a child agent and tells the server it disconnected
+    pub fn deregister_child(&mut self, instance_uid: &InstanceUid) -> Result<(), ApiClientError> {
+        self.client.session_mut().deregister_child(instance_uid)
+    }
+
+    /// Replaces the `AgentDescription` reported for a child agent
+    pub fn set_child_description(
+        &mut self,
+        instance_uid: &InstanceUid,
+        description: AgentDescription,
+    ) -> Result<(), ApiClientError> {
+        self.client.session_mut().update_child(
+            instance_uid,
+            AgentToServer {
+                agent_description: Some(description),
+                ..AgentToServer::default()
+            },
+        )
+    }
+
+    /// Replaces the health reported for a child agent
+    pub fn set_child_health(
+        &mut self,
+        instance_uid: &InstanceUid,
+        mut health: ComponentHealth,
+    ) -> Result<(), ApiClientError> {
+        if health.status_time_unix_nano == 0 {
+            health.status_time_unix_nano = get_time_nanos() as u64;
+        }
+        self.client.session_mut().update_child(
+            instance_uid,
+            AgentToServer {
+                health: Some(health),
+                ..AgentToServer::default()
+            },
+        )
+    }
+
+    /// Replaces the effective config reported for a child agent
+    pub fn set_child_effective_config(
+        &mut self,
+        instance_uid: &InstanceUid,
+        config_map: Option<AgentConfigMap>,
+    ) -> Result<(), ApiClientError> {
+        self.client.session_mut().update_child(
+            instance_uid,
+            AgentToServer {
+                effective_config: Some(EffectiveConfig { config_map }),
+                ..AgentToServer::default()
+            },
+        )
+    }
+
+    /// Replaces the store the remote config status, effective config, package statuses and
+    /// sequence number are kept in across restarts. By default they are kept in `ConnectionSettings::state_dir`, if
+    /// set. Has to be called before the first poll for the saved state to be restored.
+    pub fn set_state_store(&mut self, store: Box<dyn crate::store::StateStore>) {
+        self.client.session_mut().set_state_store(store)
+    }
+
+    /// Replaces the inventory of components available in the managed agent, e.g. one read with
+    /// `extras::config::read_available_components`. The server is sent its hash, and the full
+    /// inventory when it asks for it. The hash is computed when left empty.
+    pub fn set_available_components(
+        &mut self,
+        components: AvailableComponents,
+    ) -> Result<(), ApiClientError> {
+        self.client
+            .session_mut()
+            .set_available_components(components)
+    }
+
+    /// Declares the custom capability `capability` (a reverse FQDN such as
+    /// `io.example.diagnostics`) to the server. Custom messages for it are handed to `handler`.
+    pub fn register_custom_capability(
+        &mut self,
+        capability: &str,
+        handler: Box<dyn CustomMessageHandler + Send + Sync>,
+    ) -> Result<(), ApiClientError> {
+        self.client
+            .session_mut()
+            .register_custom_capability(capability, handler)
+    }
+
+    /// Withdraws a custom capability declared with `register_custom_capability`
+    pub fn deregister_custom_capability(&mut self, capability: &str) -> Result<(), ApiClientError> {
+        self.client
+            .session_mut()
+            .deregister_custom_capability(capability)
+    }
+
+    /// Queues a custom message for one of the registered custom capabilities.
+    ///
+    /// Only one custom message is queued at a time. While the previous one has not been sent
+    /// this fails, and the message should be offered again once `custom_message_pending()`
+    /// reports false.
+    pub fn send_custom_message(&mut self, message: CustomMessage) -> Result<(), ApiClientError> {
+        self.client.session_mut().send_custom_message(message)
+    }
+
+    /// Whether a custom message is still waiting to be sent
+    pub fn custom_message_pending(&self) -> bool {
+        self.client.session().custom_message_pending()
+    }
+
+    /// Lets the library install the packages offered by the server instead of handing them to
+    /// `on_packages_available`. Progress is reported to the server as package statuses.
+    #[cfg(feature = "packages")]
+    pub fn set_package_manager(&mut self, manager: crate::extras::packages::PackageManager) {
+        self.client.session_mut().set_package_manager(manager)
+    }
+
+    /// Opts in to the built-in handling of the restart command. `task` is stopped gracefully and
+    /// launched again in the background while polling goes on. The agent is reported unhealthy
+    /// while it restarts and healthy once the process stayed up for a moment, followed by its
+    /// full status. A process that exits right away is reported unhealthy with its exit status.
+    /// `on_command` is no longer called for restarts.
+    #[cfg(feature = "launcher")]
+    pub fn set_restart_task(&mut self, task: std::sync::Arc<crate::extras::launcher::Task>) {
+        self.client.session_mut().set_restart_task(task)
+    }
+
+    /// Writes the `other_connections` offered by the server to the file of `connections`, for the
+    /// managed agent to read. When the file changes and a restart task was set, the agent is
+    /// restarted to pick it up, otherwise `ApiCallbacks::on_other_connections_changed` is invoked.
+    /// Requires the `ACCEPTS_OTHER_CONNECTION_SETTINGS` capability.
+    #[cfg(feature = "connections")]
+    pub fn set_other_connections(
+        &mut self,
+        connections: crate::extras::connections::OtherConnections,
+    ) {
+        self.client.session_mut().set_other_connections(connections)
+    }
+
+    /// Ships the records of an installed `OtlpLogger` to the `own_logs` destinations offered by
+    /// the server. Requires the `REPORTS_OWN_LOGS` capability.
+    #[cfg(feature = "logs")]
+    pub fn set_logs_exporter(&mut self, exporter: crate::extras::logs::LogsExporter) {
+        self.client.session_mut().set_logs_exporter(exporter)
+    }
+
+    /// Asks the server to sign a client certificate for this agent. A new key pair is generated
+    /// and a certificate signing request with the instance_uid as subject is sent with the next
+    /// exchange. The signed certificate is installed like any other offered certificate, so an
+    /// agent can enroll with shared credentials and then move to its own mTLS identity.
+    ///
+    /// Requires the `AcceptsOpAMPConnectionSettings` capability.
+    #[cfg(feature = "csr")]
+    pub fn request_certificate(&mut self) -> Result<(), ApiClientError> {
+        self.client.session_mut().request_certificate()
+    }
+
+    /// Subscribes to FSM state transitions. Each receiver gets every transition from the point it
+    /// subscribed. Receivers that fall too far behind skip the oldest transitions.
+    pub fn subscribe(&self) -> broadcast::Receiver<StateTransition> {
+        self.client.session().subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(
+        kind: ServerErrorResponseType,
+        details: Option<server_error_response::Details>,
+    ) -> ServerErrorResponse {
+        ServerErrorResponse {
+            r#type: kind as i32,
+            error_message: "try later".to_string(),
+            details,
+        }
+    }
+
+    fn retry_after(nanoseconds: u64) -> Option<server_error_response::Details> {
+        Some(server_error_response::Details::RetryInfo(RetryInfo {
+            retry_after_nanoseconds: nanoseconds,
+        }))
+    }
+
+    #[test]
+    fn server_error_from_bad_request() {
+        let error = ServerError::from(&response(ServerErrorResponseType::BadRequest, None));
+        assert_eq!(error, ServerError::BadRequest("try later".to_string()));
+    }
+
+    #[test]
+    fn server_error_from_unavailable() {
+        let error = ServerError::from(&response(
+            ServerErrorResponseType::Unavailable,
+            retry_after(2_000_000_000),
+        ));
+        assert_eq!(
+            error,
+            ServerError::Unavailable {
+                message: "try later".to_string(),
+                retry_after: Some(Duration::from_secs(2)),
+            }
+        );
+
+        // A zero delay leaves the delay to the client's backoff
+        for details in [None, retry_after(0)] {
+            let error = ServerError::from(&response(ServerErrorResponseType::Unavailable, details));
+            assert_eq!(
+                error,
+                ServerError::Unavailable {
+                    message: "try later".to_string(),
+                    retry_after: None,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn server_error_from_unknown_type() {
+        let error = ServerError::from(&response(ServerErrorResponseType::Unknown, None));
+        assert_eq!(error, ServerError::Unknown("try later".to_string()));
+
+        let mut unexpected = response(ServerErrorResponseType::Unknown, None);
+        unexpected.r#type = 42;
+        assert_eq!(
+            ServerError::from(&unexpected),
+            ServerError::Unknown("try later".to_string())
+        );
+    }
+}
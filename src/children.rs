@@ -0,0 +1,180 @@
+use crate::api::{ApiClientError, ChildAgent, ChildCallbacks};
+use crate::compression::StatusCompression;
+use crate::opamp::{capabilities::Capabilities, defaults, spec::*, InstanceUid};
+
+/// The `Child` struct holds what a supervisor reports on behalf of one of its child agents and
+/// the handler that receives the server messages addressed to it.
+///
+/// Properties:
+///
+/// * `capabilities`: Capabilities the child agent advertises.
+/// * `state`: Full status of the child, sent with its own `instance_uid`.
+/// * `handler`: Receives remote configs, commands and packages for the child.
+pub(crate) struct Child {
+    pub(crate) capabilities: Capabilities,
+    pub(crate) state: AgentToServer,
+    handler: Box<dyn ChildCallbacks + Send + Sync>,
+}
+
+impl Child {
+    pub(crate) fn new(child: ChildAgent) -> (InstanceUid, Child) {
+        let state = AgentToServer {
+            instance_uid: child.instance_uid.to_wire(),
+            capabilities: child.capabilities.bits(),
+            agent_description: Some(child.description),
+            effective_config: Some(EffectiveConfig {
+                config_map: child.effective_config,
+            }),
+            remote_config_status: Some(defaults::remote_config_status()),
+            ..AgentToServer::default()
+        };
+        (
+            child.instance_uid,
+            Child {
+                capabilities: child.capabilities,
+                state,
+                handler: child.handler,
+            },
+        )
+    }
+
+    /// Folds a status update into the full state of the child and returns it addressed to the
+    /// child
+    pub(crate) fn update(&mut self, mut message: AgentToServer) -> AgentToServer {
+        message.instance_uid = self.state.instance_uid.clone();
+        StatusCompression::merge(&mut self.state, &message);
+        message
+    }
+
+    /// Moves the child to a server assigned identity
+    pub(crate) fn rename(&mut self, previous: &InstanceUid, current: &InstanceUid) {
+        log::info!(
+            "Server assigned child instance_uid {} (was {})",
+            current,
+            previous
+        );
+        self.state.instance_uid = current.to_wire();
+        self.handler.on_instance_uid_changed(previous, current);
+    }
+
+    /// Routes a message addressed to the child to its handler.
+    ///
+    /// Returns:
+    ///
+    /// The messages to send to the server on behalf of the child
+    pub(crate) fn dispatch(&mut self, msg: &ServerToAgent) -> Vec<AgentToServer> {
+        let mut outbox = vec![];
+
+        if msg.flags & (ServerToAgentFlags::ReportFullState as u64) != 0 {
+            outbox.push(self.state.clone());
+        }
+
+        if let Some(remote_config) = msg
+            .remote_config
+            .as_ref()
+            .filter(|_| self.accepts(Capabilities::ACCEPTS_REMOTE_CONFIG, "remote config"))
+        {
+            self.apply_remote_config(msg, remote_config, &mut outbox);
+        }
+
+        if msg.command.is_some() && self.accepts(Capabilities::ACCEPTS_RESTART_COMMAND, "command") {
+            let reply = self.handler.on_command(msg);
+            self.queue_reply(reply, &mut outbox);
+        }
+
+        if msg.packages_available.is_some()
+            && self.accepts(Capabilities::ACCEPTS_PACKAGES, "packages available")
+        {
+            let reply = self.handler.on_packages_available(msg);
+            self.queue_reply(reply, &mut outbox);
+        }
+
+        outbox
+    }
+
+    /// Hands a remote config to the handler and reports its status like the supervisor does
+    /// for its own configs
+    fn apply_remote_config(
+        &mut self,
+        msg: &ServerToAgent,
+        remote_config: &AgentRemoteConfig,
+        outbox: &mut Vec<AgentToServer>,
+    ) {
+        let hash = remote_config.config_hash.as_slice();
+        let already_applied = self
+            .state
+            .remote_config_status
+            .as_ref()
+            .is_some_and(|current| {
+                !hash.is_empty()
+                    && current.last_remote_config_hash == hash
+                    && current.status == RemoteConfigStatuses::Applied as i32
+            });
+        if already_applied {
+            log::debug!("Child remote config already applied. Skipping");
+            return;
+        }
+
+        // Replies for the child go out together, so only the outcome is reported
+        match self.handler.on_agent_remote_config(msg) {
+            Ok(reply) => {
+                let reports_status = reply
+                    .as_ref()
+                    .is_some_and(|reply| reply.remote_config_status.is_some());
+                if !reports_status {
+                    let status = self.remote_config_status(hash, RemoteConfigStatuses::Applied, "");
+                    outbox.push(status);
+                }
+                self.queue_reply(Ok(reply), outbox);
+            }
+            Err(e) => {
+                log::warn!("Child callback error: {}", e);
+                let status =
+                    self.remote_config_status(hash, RemoteConfigStatuses::Failed, &e.to_string());
+                outbox.push(status);
+            }
+        }
+    }
+
+    fn remote_config_status(
+        &mut self,
+        hash: &[u8],
+        status: RemoteConfigStatuses,
+        error_message: &str,
+    ) -> AgentToServer {
+        self.update(AgentToServer {
+            remote_config_status: Some(RemoteConfigStatus {
+                last_remote_config_hash: hash.to_vec(),
+                status: status.into(),
+                error_message: error_message.to_string(),
+            }),
+            ..AgentToServer::default()
+        })
+    }
+
+    fn queue_reply(
+        &mut self,
+        reply: Result<Option<AgentToServer>, ApiClientError>,
+        outbox: &mut Vec<AgentToServer>,
+    ) {
+        match reply {
+            Ok(Some(reply)) => outbox.push(self.update(reply)),
+            Ok(None) => {}
+            Err(e) => {
+                log::warn!("Child callback error: {}", e);
+            }
+        }
+    }
+
+    fn accepts(&self, capability: Capabilities, what: &str) -> bool {
+        let accepted = self.capabilities.contains(capability);
+        if !accepted {
+            log::warn!(
+                "Rejecting {} for child agent: capability {:?} not declared",
+                what,
+                capability
+            );
+        }
+        accepted
+    }
+}
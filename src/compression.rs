@@ -0,0 +1,271 @@
+use crate::opamp::spec::*;
+use prost::Message;
+use std::collections::HashMap;
+
+/// The `StatusCompression` struct implements OpAMP status compression. It remembers the last value
+/// of each status section that was delivered to the server so unchanged sections can be left out
+/// of subsequent messages.
+///
+/// Properties:
+///
+/// * `agent_description`: Last delivered `AgentDescription`.
+/// * `health`: Last delivered `ComponentHealth`.
+/// * `effective_config`: Last delivered `EffectiveConfig`.
+/// * `remote_config_status`: Last delivered `RemoteConfigStatus`.
+/// * `package_statuses`: Last delivered `PackageStatuses`.
+/// * `custom_capabilities`: Last delivered `CustomCapabilities`.
+/// * `available_components`: Hash of the last delivered `AvailableComponents`.
+#[derive(Default)]
+pub(crate) struct StatusCompression {
+    agent_description: Option<AgentDescription>,
+    health: Option<ComponentHealth>,
+    effective_config: Option<EffectiveConfig>,
+    remote_config_status: Option<RemoteConfigStatus>,
+    package_statuses: Option<PackageStatuses>,
+    custom_capabilities: Option<CustomCapabilities>,
+    available_components: Option<Vec<u8>>,
+}
+
+/// Drops `section` from an outbound message when it matches what the server already has
+fn omit_unchanged<T: PartialEq>(section: &mut Option<T>, delivered: &Option<T>) {
+    if section.is_some() && section == delivered {
+        *section = None;
+    }
+}
+
+/// Records a section that was delivered to the server
+fn remember<T: Clone>(delivered: &mut Option<T>, section: &Option<T>) {
+    if section.is_some() {
+        delivered.clone_from(section);
+    }
+}
+
+impl StatusCompression {
+    /// Forgets everything delivered so far. The next message carrying a section sends it in full
+    pub(crate) fn reset(&mut self) {
+        *self = StatusCompression::default();
+    }
+
+    /// Removes the sections of `message` that are unchanged since they were last delivered
+    pub(crate) fn compress(&self, message: &mut AgentToServer) {
+        omit_unchanged(&mut message.agent_description, &self.agent_description);
+        omit_unchanged(&mut message.health, &self.health);
+        omit_unchanged(&mut message.effective_config, &self.effective_config);
+        omit_unchanged(
+            &mut message.remote_config_status,
+            &self.remote_config_status,
+        );
+        omit_unchanged(&mut message.package_statuses, &self.package_statuses);
+        omit_unchanged(&mut message.custom_capabilities, &self.custom_capabilities);
+        // The inventory is identified by its hash. A full inventory asked for by the server is
+        // always sent
+        let delivered = message
+            .available_components
+            .as_ref()
+            .is_some_and(|inventory| {
+                inventory.components.is_empty()
+                    && self.available_components.as_ref() == Some(&inventory.hash)
+            });
+        if delivered {
+            message.available_components = None;
+        }
+    }
+
+    /// Records the sections of a message the server has received
+    pub(crate) fn acknowledge(&mut self, message: &AgentToServer) {
+        remember(&mut self.agent_description, &message.agent_description);
+        remember(&mut self.health, &message.health);
+        remember(&mut self.effective_config, &message.effective_config);
+        remember(
+            &mut self.remote_config_status,
+            &message.remote_config_status,
+        );
+        remember(&mut self.package_statuses, &message.package_statuses);
+        remember(&mut self.custom_capabilities, &message.custom_capabilities);
+        if let Some(inventory) = &message.available_components {
+            self.available_components = Some(inventory.hash.clone());
+        }
+    }
+
+    /// Folds the sections of an outbound message into the cached full agent state so that a
+    /// later full state report reflects them
+    pub(crate) fn merge(state: &mut AgentToServer, message: &AgentToServer) {
+        remember(&mut state.agent_description, &message.agent_description);
+        remember(&mut state.health, &message.health);
+        remember(&mut state.effective_config, &message.effective_config);
+        remember(
+            &mut state.remote_config_status,
+            &message.remote_config_status,
+        );
+        remember(&mut state.package_statuses, &message.package_statuses);
+        remember(&mut state.custom_capabilities, &message.custom_capabilities);
+        // Full state reports carry the hash of the inventory only
+        if let Some(inventory) = &message.available_components {
+            state.available_components = Some(AvailableComponents {
+                components: HashMap::new(),
+                hash: inventory.hash.clone(),
+            });
+        }
+    }
+}
+
+/// Computes the hash identifying an inventory of available components. Components are visited
+/// in name order so that equal inventories always hash alike.
+pub(crate) fn components_hash(components: &HashMap<String, ComponentDetails>) -> Vec<u8> {
+    let mut crc = libdeflater::Crc::new();
+    hash_components(&mut crc, components);
+    crc.sum().to_be_bytes().to_vec()
+}
+
+fn hash_components(crc: &mut libdeflater::Crc, components: &HashMap<String, ComponentDetails>) {
+    let mut names: Vec<&String> = components.keys().collect();
+    names.sort();
+    for name in names {
+        let details = &components[name];
+        crc.update(name.as_bytes());
+        for metadata in &details.metadata {
+            crc.update(&metadata.encode_to_vec());
+        }
+        // Delimit the nesting so that moving a component to another level changes the hash
+        crc.update(b"{");
+        hash_components(crc, &details.sub_component_map);
+        crc.update(b"}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn health(healthy: bool) -> Option<ComponentHealth> {
+        Some(ComponentHealth {
+            healthy,
+            ..ComponentHealth::default()
+        })
+    }
+
+    fn config(body: &str) -> Option<EffectiveConfig> {
+        Some(EffectiveConfig {
+            config_map: Some(AgentConfigMap {
+                config_map: HashMap::from([(
+                    "".to_string(),
+                    AgentConfigFile {
+                        body: body.as_bytes().to_vec(),
+                        content_type: "text/yaml".to_string(),
+                    },
+                )]),
+            }),
+        })
+    }
+
+    fn component(name: &str, sub_components: &[&str]) -> (String, ComponentDetails) {
+        let sub_component_map = sub_components
+            .iter()
+            .map(|sub| (sub.to_string(), ComponentDetails::default()))
+            .collect();
+        (
+            name.to_string(),
+            ComponentDetails {
+                metadata: vec![],
+                sub_component_map,
+            },
+        )
+    }
+
+    #[test]
+    fn compress_omits_acknowledged_sections() {
+        let mut compression = StatusCompression::default();
+        let sent = AgentToServer {
+            health: health(true),
+            effective_config: config("a: 1"),
+            ..AgentToServer::default()
+        };
+        compression.acknowledge(&sent);
+
+        let mut next = AgentToServer {
+            health: health(true),
+            effective_config: config("a: 2"),
+            ..AgentToServer::default()
+        };
+        compression.compress(&mut next);
+        assert_eq!(next.health, None);
+        assert_eq!(next.effective_config, config("a: 2"));
+    }
+
+    #[test]
+    fn compress_keeps_unacknowledged_sections() {
+        let compression = StatusCompression::default();
+        let mut message = AgentToServer {
+            health: health(false),
+            ..AgentToServer::default()
+        };
+        compression.compress(&mut message);
+        assert_eq!(message.health, health(false));
+    }
+
+    #[test]
+    fn reset_sends_everything_again() {
+        let mut compression = StatusCompression::default();
+        let sent = AgentToServer {
+            health: health(true),
+            ..AgentToServer::default()
+        };
+        compression.acknowledge(&sent);
+        compression.reset();
+
+        let mut next = sent.clone();
+        compression.compress(&mut next);
+        assert_eq!(next.health, health(true));
+    }
+
+    #[test]
+    fn compress_omits_acknowledged_inventory_hash_only() {
+        let mut compression = StatusCompression::default();
+        let components = HashMap::from([component("receivers", &["otlp"])]);
+        let hash = components_hash(&components);
+        compression.acknowledge(&AgentToServer {
+            available_components: Some(AvailableComponents {
+                components: components.clone(),
+                hash: hash.clone(),
+            }),
+            ..AgentToServer::default()
+        });
+
+        let mut hash_only = AgentToServer {
+            available_components: Some(AvailableComponents {
+                components: HashMap::new(),
+                hash: hash.clone(),
+            }),
+            ..AgentToServer::default()
+        };
+        compression.compress(&mut hash_only);
+        assert_eq!(hash_only.available_components, None);
+
+        // The full inventory was asked for by the server
+        let mut full = AgentToServer {
+            available_components: Some(AvailableComponents { components, hash }),
+            ..AgentToServer::default()
+        };
+        compression.compress(&mut full);
+        assert!(full.available_components.is_some());
+    }
+
+    #[test]
+    fn components_hash_ignores_order() {
+        let forward = HashMap::from_iter((0..32).map(|i| component(&format!("c{}", i), &[])));
+        let backward =
+            HashMap::from_iter((0..32).rev().map(|i| component(&format!("c{}", i), &[])));
+        assert_eq!(components_hash(&forward), components_hash(&backward));
+
+        let nested = HashMap::from([component("receivers", &["otlp", "prometheus"])]);
+        let reordered = HashMap::from([component("receivers", &["prometheus", "otlp"])]);
+        assert_eq!(components_hash(&nested), components_hash(&reordered));
+    }
+
+    #[test]
+    fn components_hash_tracks_nesting() {
+        let nested = HashMap::from([component("receivers", &["otlp"])]);
+        let flat = HashMap::from([component("receivers", &[]), component("otlp", &[])]);
+        assert_ne!(components_hash(&nested), components_hash(&flat));
+    }
+}
@@ -0,0 +1,371 @@
+//! Building the `AgentDescription` reported to the server.
+//!
+//! A [`DescriptionBuilder`] starts from the identifying `service.*` attributes, takes attributes
+//! from the application and runs a list of [`Detector`]s for everything that can be discovered
+//! about the host, the process and the environment it runs in.
+
+use crate::api::ApiClientError;
+use crate::opamp::spec::*;
+use std::fs;
+use sysinfo::{System, SystemExt};
+
+/// A `Detector` discovers resource attributes of the environment the agent runs in. Detectors
+/// that fail are skipped when the description is built.
+pub trait Detector: Send + Sync {
+    /// Name of the detector, used in logs
+    fn name(&self) -> &str;
+    /// Reports the attributes found, as non-identifying attributes
+    fn detect(&self) -> Result<Vec<KeyValue>, ApiClientError>;
+}
+
+/// Builds a string valued `KeyValue`
+pub(crate) fn attribute(key: &str, value: impl Into<String>) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue {
+            value: Some(any_value::Value::StringValue(value.into())),
+        }),
+    }
+}
+
+/// The `DescriptionBuilder` struct assembles an `AgentDescription`.
+///
+/// Properties:
+///
+/// * `identifying_attributes`: Attributes that identify the agent, starting with `service.*`.
+/// * `non_identifying_attributes`: Attributes set by the application.
+/// * `detectors`: Detectors run by `build`. Their attributes never replace ones set explicitly.
+pub struct DescriptionBuilder {
+    identifying_attributes: Vec<KeyValue>,
+    non_identifying_attributes: Vec<KeyValue>,
+    detectors: Vec<Box<dyn Detector>>,
+}
+
+impl DescriptionBuilder {
+    /// Starts a description for the service `name` at `version`. `instance_id` has to be the
+    /// instance id the agent connects with.
+    pub fn new(name: &str, version: &str, instance_id: &str) -> DescriptionBuilder {
+        DescriptionBuilder {
+            identifying_attributes: vec![
+                attribute("service.name", name),
+                attribute("service.version", version),
+                attribute("service.instance.id", instance_id),
+            ],
+            non_identifying_attributes: vec![],
+            detectors: vec![],
+        }
+    }
+
+    /// Adds the built-in detectors: operating system, host, process, container and Kubernetes
+    pub fn with_default_detectors(self) -> DescriptionBuilder {
+        self.detector(Box::new(OsDetector))
+            .detector(Box::new(HostDetector))
+            .detector(Box::new(ProcessDetector))
+            .detector(Box::new(ContainerDetector))
+            .detector(Box::new(KubernetesDetector))
+    }
+
+    /// Sets an identifying attribute, replacing an earlier value for `key`
+    pub fn identifying_attribute(mut self, key: &str, value: impl Into<String>) -> Self {
+        upsert(&mut self.identifying_attributes, attribute(key, value));
+        self
+    }
+
+    /// Sets a non-identifying attribute, replacing an earlier value for `key`
+    pub fn attribute(mut self, key: &str, value: impl Into<String>) -> Self {
+        upsert(&mut self.non_identifying_attributes, attribute(key, value));
+        self
+    }
+
+    /// Adds a detector that runs when the description is built
+    pub fn detector(mut self, detector: Box<dyn Detector>) -> Self {
+        self.detectors.push(detector);
+        self
+    }
+
+    /// Runs the detectors and returns the description. Detector failures are logged and skipped.
+    pub fn build(self) -> AgentDescription {
+        let mut non_identifying_attributes = self.non_identifying_attributes;
+        for detector in &self.detectors {
+            let detected = match detector.detect() {
+                Ok(detected) => detected,
+                Err(e) => {
+                    log::debug!("Skipping {} detector: {}", detector.name(), e);
+                    continue;
+                }
+            };
+            for kv in detected {
+                let known = self
+                    .identifying_attributes
+                    .iter()
+                    .chain(non_identifying_attributes.iter())
+                    .any(|existing| existing.key == kv.key);
+                if !known {
+                    non_identifying_attributes.push(kv);
+                }
+            }
+        }
+
+        AgentDescription {
+            identifying_attributes: self.identifying_attributes,
+            non_identifying_attributes,
+        }
+    }
+}
+
+fn upsert(attributes: &mut Vec<KeyValue>, kv: KeyValue) {
+    match attributes
+        .iter_mut()
+        .find(|existing| existing.key == kv.key)
+    {
+        Some(existing) => *existing = kv,
+        None => attributes.push(kv),
+    }
+}
+
+/// Detects `os.type`, `os.version` and `os.description`
+pub struct OsDetector;
+
+impl Detector for OsDetector {
+    fn name(&self) -> &str {
+        "os"
+    }
+
+    fn detect(&self) -> Result<Vec<KeyValue>, ApiClientError> {
+        let sys = System::new();
+        let mut attributes = vec![attribute("os.type", std::env::consts::OS)];
+        // sysinfo cannot determine these on every platform. Leave them out when unknown
+        if let Some(kernel_version) = sys.kernel_version() {
+            attributes.push(attribute("os.version", kernel_version));
+        }
+        if let Some(description) = sys.long_os_version() {
+            attributes.push(attribute("os.description", description));
+        }
+        Ok(attributes)
+    }
+}
+
+/// Detects `host.name` and `host.arch`
+pub struct HostDetector;
+
+impl Detector for HostDetector {
+    fn name(&self) -> &str {
+        "host"
+    }
+
+    fn detect(&self) -> Result<Vec<KeyValue>, ApiClientError> {
+        let arch = match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "aarch64" => "arm64",
+            "x86" => "x86",
+            "arm" => "arm32",
+            "powerpc" => "ppc32",
+            "powerpc64" => "ppc64",
+            "s390x" => "s390x",
+            other => other,
+        };
+        let mut attributes = vec![attribute("host.arch", arch)];
+        if let Some(host_name) = System::new().host_name() {
+            attributes.push(attribute("host.name", host_name));
+        }
+        Ok(attributes)
+    }
+}
+
+/// Detects `process.pid`, `process.executable.path` and `process.executable.name`
+pub struct ProcessDetector;
+
+impl Detector for ProcessDetector {
+    fn name(&self) -> &str {
+        "process"
+    }
+
+    fn detect(&self) -> Result<Vec<KeyValue>, ApiClientError> {
+        let mut attributes = vec![KeyValue {
+            key: "process.pid".to_string(),
+            value: Some(AnyValue {
+                value: Some(any_value::Value::IntValue(std::process::id() as i64)),
+            }),
+        }];
+        let executable = std::env::current_exe().map_err(|e| {
+            ApiClientError::new(line!(), format!("No executable path: {}", e).as_str())
+        })?;
+        attributes.push(attribute(
+            "process.executable.path",
+            executable.to_string_lossy(),
+        ));
+        if let Some(name) = executable.file_name() {
+            attributes.push(attribute("process.executable.name", name.to_string_lossy()));
+        }
+        Ok(attributes)
+    }
+}
+
+/// Detects `container.id` from the cgroup of the process (Linux only)
+pub struct ContainerDetector;
+
+impl ContainerDetector {
+    /// Finds the container id ending a cgroup path of /proc/self/cgroup, e.g. `/docker/<id>` or
+    /// `/system.slice/docker-<id>.scope`
+    fn id_from_cgroup(listing: &str) -> Option<String> {
+        listing
+            .lines()
+            .filter_map(|line| line.splitn(3, ':').nth(2))
+            .filter_map(|path| path.rsplit('/').next())
+            .find_map(|name| Self::scope_id(name).or_else(|| Self::is_id(name).then_some(name)))
+            .map(|id| id.to_string())
+    }
+
+    /// Finds the container id in /proc/self/mountinfo, from the files a runtime keeps per
+    /// container, e.g. `/var/lib/docker/containers/<id>/hostname`, or from a
+    /// `/docker-<id>.scope` cgroup. Other ids, such as those of overlay layers, are ignored.
+    fn id_from_mountinfo(listing: &str) -> Option<String> {
+        listing
+            .lines()
+            .flat_map(|line| line.split(' '))
+            .find_map(|field| {
+                let segments = field.split('/').collect::<Vec<_>>();
+                segments
+                    .windows(3)
+                    .find(|w| w[0].ends_with("containers") && Self::is_id(w[1]))
+                    .map(|w| w[1])
+                    .or_else(|| segments.iter().find_map(|segment| Self::scope_id(segment)))
+            })
+            .map(|id| id.to_string())
+    }
+
+    /// Extracts the id of a systemd scope named `<runtime>-<id>.scope`
+    fn scope_id(name: &str) -> Option<&str> {
+        let (_, id) = name.strip_suffix(".scope")?.rsplit_once('-')?;
+        Self::is_id(id).then_some(id)
+    }
+
+    /// Whether `id` is a 64 character hexadecimal container id
+    fn is_id(id: &str) -> bool {
+        id.len() == 64 && id.bytes().all(|b| b.is_ascii_hexdigit())
+    }
+}
+
+impl Detector for ContainerDetector {
+    fn name(&self) -> &str {
+        "container"
+    }
+
+    fn detect(&self) -> Result<Vec<KeyValue>, ApiClientError> {
+        // cgroup v1 names the container in /proc/self/cgroup, cgroup v2 only in the mounts
+        let id = fs::read_to_string("/proc/self/cgroup")
+            .ok()
+            .and_then(|listing| Self::id_from_cgroup(&listing))
+            .or_else(|| {
+                fs::read_to_string("/proc/self/mountinfo")
+                    .ok()
+                    .and_then(|listing| Self::id_from_mountinfo(&listing))
+            })
+            .ok_or_else(|| ApiClientError::new(line!(), "Not running in a container"))?;
+        Ok(vec![attribute("container.id", id)])
+    }
+}
+
+/// Detects Kubernetes attributes from environment variables populated with the downward API:
+/// `K8S_POD_NAME`, `K8S_POD_UID`, `K8S_NAMESPACE_NAME` and `K8S_NODE_NAME` (or `POD_NAME`,
+/// `POD_UID`, `POD_NAMESPACE` and `NODE_NAME`)
+pub struct KubernetesDetector;
+
+impl Detector for KubernetesDetector {
+    fn name(&self) -> &str {
+        "kubernetes"
+    }
+
+    fn detect(&self) -> Result<Vec<KeyValue>, ApiClientError> {
+        let variables = [
+            ("k8s.pod.name", ["K8S_POD_NAME", "POD_NAME"]),
+            ("k8s.pod.uid", ["K8S_POD_UID", "POD_UID"]),
+            (
+                "k8s.namespace.name",
+                ["K8S_NAMESPACE_NAME", "POD_NAMESPACE"],
+            ),
+            ("k8s.node.name", ["K8S_NODE_NAME", "NODE_NAME"]),
+        ];
+        let attributes = variables
+            .iter()
+            .filter_map(|(key, names)| {
+                names
+                    .iter()
+                    .find_map(|name| std::env::var(name).ok().filter(|v| !v.is_empty()))
+                    .map(|value| attribute(key, value))
+            })
+            .collect::<Vec<_>>();
+        if attributes.is_empty() {
+            return Err(ApiClientError::new(line!(), "Not running in Kubernetes"));
+        }
+        Ok(attributes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "9a7c2b4e1f0d3c5b8a6e4d2c0b1a3f5e7d9c8b6a4f2e0d1c3b5a7f9e8d6c4b2a";
+    const LAYER: &str = "3f1e5d7c9b2a4e6d8c0b1a3f5e7d9c2b4a6f8e0d1c3b5a7f9e2d4c6b8a0f1e3d";
+
+    #[test]
+    fn container_id_from_cgroup_v1() {
+        let listing = format!(
+            "12:memory:/docker/{ID}\n11:cpu,cpuacct:/docker/{ID}\n1:name=systemd:/docker/{ID}\n"
+        );
+        assert_eq!(
+            ContainerDetector::id_from_cgroup(&listing).as_deref(),
+            Some(ID)
+        );
+
+        let listing = format!("0::/system.slice/docker-{ID}.scope\n");
+        assert_eq!(
+            ContainerDetector::id_from_cgroup(&listing).as_deref(),
+            Some(ID)
+        );
+
+        let listing =
+            format!("3:cpu:/kubepods/burstable/pod0f4a1e32-7c55-4c77-9a5b-0e1f2d3c4b5a/{ID}\n");
+        assert_eq!(
+            ContainerDetector::id_from_cgroup(&listing).as_deref(),
+            Some(ID)
+        );
+    }
+
+    #[test]
+    fn no_container_id_from_cgroup_v2_namespace() {
+        assert_eq!(ContainerDetector::id_from_cgroup("0::/\n"), None);
+        assert_eq!(
+            ContainerDetector::id_from_cgroup("0::/user.slice/user-1000.slice/session-2.scope\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn container_id_from_mountinfo_skips_overlay_layers() {
+        let listing = format!(
+            "1090 1021 0:64 / / rw,relatime master:435 - overlay overlay rw,\
+             lowerdir=/var/lib/docker/overlay2/l/ABCDEF:/var/lib/docker/overlay2/l/GHIJKL,\
+             upperdir=/var/lib/docker/overlay2/{LAYER}/diff,\
+             workdir=/var/lib/docker/overlay2/{LAYER}/work\n\
+             1091 1090 0:67 / /proc rw,nosuid,nodev,noexec,relatime - proc proc rw\n\
+             1095 1090 0:30 / /sys/fs/cgroup ro,nosuid,nodev,noexec,relatime - cgroup2 cgroup rw\n\
+             1101 1090 254:1 /docker/containers/{ID}/resolv.conf /etc/resolv.conf rw,relatime - ext4 /dev/vda1 rw\n\
+             1102 1090 254:1 /docker/containers/{ID}/hostname /etc/hostname rw,relatime - ext4 /dev/vda1 rw\n"
+        );
+        assert_eq!(
+            ContainerDetector::id_from_mountinfo(&listing).as_deref(),
+            Some(ID)
+        );
+    }
+
+    #[test]
+    fn no_container_id_from_host_mountinfo() {
+        let listing = format!(
+            "29 1 254:1 / / rw,relatime shared:1 - ext4 /dev/vda1 rw\n\
+             412 29 0:52 / /var/lib/docker/overlay2/{LAYER}/merged rw,relatime - overlay overlay rw\n"
+        );
+        assert_eq!(ContainerDetector::id_from_mountinfo(&listing), None);
+    }
+}
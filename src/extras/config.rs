@@ -1,3 +1,4 @@
+use crate::opamp::spec::*;
 use serde_yaml::Value;
 use std::collections::HashMap;
 use std::fs::{self, File};
@@ -19,19 +20,23 @@ pub fn read_yaml_files(folder_path: &str) -> HashMap<String, String> {
     let mut data_map: HashMap<String, String> = HashMap::new();
 
     if let Ok(entries) = fs::read_dir(folder_path) {
-        for entry in entries {
-            if let Ok(entry) = entry {
-                let file_path = entry.path();
-                if file_path.is_file() {
-                    if let Some(extension) = file_path.extension() {
-                        if extension == "yaml" || extension == "yml" {
-                            let contents = read_file_contents(&file_path);
+        for entry in entries.flatten() {
+            let file_path = entry.path();
+            if file_path.is_file() {
+                if let Some(extension) = file_path.extension() {
+                    if extension == "yaml" || extension == "yml" {
+                        let contents = match read_file_contents(&file_path) {
+                            Ok(contents) => contents,
+                            Err(e) => {
+                                log::warn!("Skipping {}: {}", file_path.display(), e);
+                                continue;
+                            }
+                        };
 
-                            // Extract the file name without extension
-                            let file_name = get_relative_file_name(&file_path, folder_path);
+                        // Extract the file name without extension
+                        let file_name = get_relative_file_name(&file_path, folder_path);
 
-                            data_map.insert(file_name, contents);
-                        }
+                        data_map.insert(file_name, contents);
                     }
                 }
             }
@@ -48,14 +53,14 @@ pub fn read_yaml_files(folder_path: &str) -> HashMap<String, String> {
 ///
 /// * `file_path`: `file_path` is a reference to a `Path` object, which represents the path to a file.
 /// * `folder_path`: The `folder_path` parameter is a string representing the path of a folder on the
-/// file system.
+///   file system.
 ///
 /// Returns:
 ///
 /// The function `get_relative_file_name` returns a `String` that represents the relative path of a file
 /// with respect to a folder.
 fn get_relative_file_name(file_path: &Path, folder_path: &str) -> String {
-    let file_path = file_path.strip_prefix(folder_path).unwrap();
+    let file_path = file_path.strip_prefix(folder_path).unwrap_or(file_path);
     file_path.to_string_lossy().to_string().replace('\\', "/")
 }
 
@@ -64,18 +69,17 @@ fn get_relative_file_name(file_path: &Path, folder_path: &str) -> String {
 /// Arguments:
 ///
 /// * `file_path`: A reference to a Path object that represents the path to the file that needs to be
-/// read.
+///   read.
 ///
 /// Returns:
 ///
-/// The function `read_file_contents` returns a `String` that contains the contents of the file located
-/// at the given `file_path`.
-fn read_file_contents(file_path: &Path) -> String {
-    let mut file = fs::File::open(file_path).expect("Failed to open file");
+/// The function `read_file_contents` returns a `Result` with a `String` that contains the contents of
+/// the file located at the given `file_path`, or the IO error that prevented reading it.
+fn read_file_contents(file_path: &Path) -> std::io::Result<String> {
+    let mut file = fs::File::open(file_path)?;
     let mut contents = String::new();
-    file.read_to_string(&mut contents)
-        .expect("Failed to read file");
-    contents
+    file.read_to_string(&mut contents)?;
+    Ok(contents)
 }
 
 /// The function merges two values, either as a mapping or a sequence, into a single value.
@@ -83,17 +87,17 @@ fn read_file_contents(file_path: &Path) -> String {
 /// Arguments:
 ///
 /// * `destination`: The destination parameter is a Value type, which can be either a Mapping or a
-/// Sequence. It represents the value that will be updated with the values from the source parameter.
+///   Sequence. It represents the value that will be updated with the values from the source parameter.
 /// * `source`: The `source` parameter is a `Value` type, which can be either a `Mapping` or a
-/// `Sequence` containing key-value pairs or a list of values respectively. It is the value that needs
-/// to be merged into the `destination` value.
+///   `Sequence` containing key-value pairs or a list of values respectively. It is the value that needs
+///   to be merged into the `destination` value.
 ///
 /// Returns:
 ///
 /// a `Value` which is the result of merging the `destination` and `source` values. The returned value
 /// can be one of the following:
 /// - If both `destination` and `source` are mappings, a new mapping is returned with the merged
-/// key-value pairs.
+///   key-value pairs.
 /// - If both `destination` and `source` are sequences, a new sequence is returned with the elements of
 fn merge_values(destination: Value, source: Value) -> Value {
     match (destination, source) {
@@ -122,7 +126,7 @@ fn merge_values(destination: Value, source: Value) -> Value {
 ///
 /// * `current_config`: A string representing the path to the current configuration file.
 /// * `new_config`: The path to the file containing the new configuration data that needs to be merged
-/// with the current configuration.
+///   with the current configuration.
 ///
 /// Returns:
 ///
@@ -150,3 +154,96 @@ pub fn merge(current_config: &str, new_config: &str) -> Result<Value, Box<dyn st
 
     Ok(merged_value)
 }
+
+/// The function reads the component inventory printed by `otelcol components` (or any YAML file
+/// of the same shape) for reporting as available components.
+///
+/// Arguments:
+///
+/// * `path`: The path to the YAML file. Every top-level sequence (`receivers`, `processors`,
+///   `exporters`, ...) becomes a component kind holding its entries by `name`. The other fields of
+///   an entry are reported as metadata, nested mappings with dotted keys.
+///
+/// Returns:
+///
+/// a `Result` containing the `AvailableComponents`, with an empty hash that the client fills in,
+/// or a `Box` that implements the `std::error::Error` trait.
+pub fn read_available_components(
+    path: &str,
+) -> Result<AvailableComponents, Box<dyn std::error::Error>> {
+    let contents = fs::read_to_string(path)?;
+    let inventory = match serde_yaml::from_str::<Value>(&contents)? {
+        Value::Mapping(inventory) => inventory,
+        _ => return Err(format!("{}: expected a mapping of component kinds", path).into()),
+    };
+
+    let mut components = HashMap::new();
+    for (kind, entries) in inventory {
+        let (Some(kind), Value::Sequence(entries)) = (kind.as_str(), entries) else {
+            // e.g. buildinfo
+            continue;
+        };
+        let mut sub_component_map = HashMap::new();
+        for entry in entries {
+            let Value::Mapping(entry) = entry else {
+                continue;
+            };
+            let Some(name) = entry
+                .get("name")
+                .and_then(Value::as_str)
+                .map(str::to_string)
+            else {
+                continue;
+            };
+            let mut metadata = vec![];
+            for (key, value) in &entry {
+                if let Some(key) = key.as_str().filter(|key| *key != "name") {
+                    flatten_metadata(key, value, &mut metadata);
+                }
+            }
+            sub_component_map.insert(
+                name,
+                ComponentDetails {
+                    metadata,
+                    sub_component_map: HashMap::new(),
+                },
+            );
+        }
+        components.insert(
+            kind.to_string(),
+            ComponentDetails {
+                metadata: vec![],
+                sub_component_map,
+            },
+        );
+    }
+
+    Ok(AvailableComponents {
+        components,
+        hash: vec![],
+    })
+}
+
+/// Adds the scalar values below `key` to `metadata`, joining the keys of nested mappings with dots
+fn flatten_metadata(key: &str, value: &Value, metadata: &mut Vec<KeyValue>) {
+    let text = match value {
+        Value::Mapping(mapping) => {
+            for (nested, value) in mapping {
+                if let Some(nested) = nested.as_str() {
+                    flatten_metadata(&format!("{}.{}", key, nested), value, metadata);
+                }
+            }
+            return;
+        }
+        Value::String(text) => text.clone(),
+        Value::Bool(flag) => flag.to_string(),
+        Value::Number(number) => number.to_string(),
+        _ => return,
+    };
+    metadata.push(KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue {
+            value: Some(any_value::Value::StringValue(text)),
+        }),
+    });
+}
@@ -0,0 +1,348 @@
+//! Materializes the `other_connections` of a `ConnectionSettingsOffers` for the managed agent.
+
+use crate::api::ApiClientError;
+use crate::opamp::spec::*;
+use crate::tls;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Prefix of the variables written to an env file
+const ENV_PREFIX: &str = "OPAMP_";
+
+/// The format the offered connections are written in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// `OPAMP_<NAME>_ENDPOINT="..."` lines, e.g. for an `EnvironmentFile`
+    Env,
+    /// A `connections:` map keyed by connection name, e.g. to merge into the agent config
+    Yaml,
+}
+
+/// The `OtherConnections` struct writes the destinations offered in `other_connections` to a file
+/// the agent reads on start. Offered certificates are saved as `<name>.crt`, `<name>.key` and
+/// `<name>-ca.crt` in `certificate_dir` and referenced by path.
+///
+/// Properties:
+///
+/// * `path`: File the connections are written to.
+/// * `format`: Format of that file.
+/// * `certificate_dir`: Directory holding the offered certificates.
+/// * `hash`: Hash of the offers last written, as provided by the server.
+pub struct OtherConnections {
+    path: PathBuf,
+    format: Format,
+    certificate_dir: PathBuf,
+    hash: Vec<u8>,
+}
+
+/// A connection as written in the YAML format
+#[derive(Serialize)]
+struct Connection {
+    endpoint: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls: Option<Certificate>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    settings: BTreeMap<String, String>,
+}
+
+/// Paths of a saved certificate
+#[derive(Serialize)]
+struct Certificate {
+    cert_file: String,
+    key_file: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ca_file: Option<String>,
+}
+
+impl OtherConnections {
+    /// Writes offered connections to `path`. Certificates go to a `certs` directory next to it.
+    pub fn new(path: impl Into<PathBuf>, format: Format) -> OtherConnections {
+        let path = path.into();
+        let certificate_dir = path
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+            .join("certs");
+        OtherConnections {
+            path,
+            format,
+            certificate_dir,
+            hash: vec![],
+        }
+    }
+
+    /// Saves offered certificates in `dir` instead
+    pub fn with_certificate_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.certificate_dir = dir.into();
+        self
+    }
+
+    /// Path of the file the agent should read
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Hash of the offers last written
+    pub fn hash(&self) -> &[u8] {
+        &self.hash
+    }
+
+    /// Replaces the hash of the offers last written, e.g. with the one saved by a previous run
+    pub fn restore(&mut self, hash: Vec<u8>) {
+        self.hash = hash;
+    }
+
+    /// Writes the offered connections unless the offers carry the hash last written or the file
+    /// already has the same contents.
+    ///
+    /// Returns:
+    ///
+    /// Whether the file changed, in which case the agent has to reload it
+    pub fn apply(&mut self, offers: &ConnectionSettingsOffers) -> Result<bool, ApiClientError> {
+        if !offers.hash.is_empty() && offers.hash == self.hash {
+            log::debug!("Other connection settings unchanged");
+            return Ok(false);
+        }
+
+        let mut connections = BTreeMap::new();
+        let mut certificate_names = BTreeSet::new();
+        for (name, offer) in &offers.other_connections {
+            let tls = match &offer.certificate {
+                Some(certificate) => {
+                    if !certificate_names.insert(file_name(name)) {
+                        return Err(ApiClientError::new(
+                            line!(),
+                            format!("Certificate of {} would replace another one", name).as_str(),
+                        ));
+                    }
+                    Some(self.save_certificate(name, certificate)?)
+                }
+                None => None,
+            };
+            let headers = offer
+                .headers
+                .iter()
+                .flat_map(|headers| &headers.headers)
+                .map(|header| (header.key.clone(), header.value.clone()))
+                .collect();
+            connections.insert(
+                name.clone(),
+                Connection {
+                    endpoint: offer.destination_endpoint.clone(),
+                    headers,
+                    tls,
+                    settings: offer.other_settings.clone().into_iter().collect(),
+                },
+            );
+        }
+        let contents = match self.format {
+            Format::Env => render_env(&connections)?,
+            Format::Yaml => render_yaml(&connections)?,
+        };
+
+        let changed = fs::read(&self.path).ok().as_ref() != Some(&contents);
+        if changed {
+            if let Some(parent) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                fs::create_dir_all(parent).map_err(|e| {
+                    ApiClientError::new(
+                        line!(),
+                        format!("Unable to create {}: {}", parent.display(), e).as_str(),
+                    )
+                })?;
+            }
+            crate::store::write_atomic(&self.path, &contents, None)?;
+            log::info!(
+                "Wrote {} other connection(s) to {}",
+                connections.len(),
+                self.path.display()
+            );
+        }
+        self.hash = offers.hash.clone();
+        Ok(changed)
+    }
+
+    /// Checks and saves an offered certificate
+    fn save_certificate(
+        &self,
+        name: &str,
+        certificate: &TlsCertificate,
+    ) -> Result<Certificate, ApiClientError> {
+        tls::validate(certificate)?;
+        let name = file_name(name);
+        tls::persist(&self.certificate_dir, &name, certificate)?;
+        let path = |suffix: &str| {
+            self.certificate_dir
+                .join(format!("{}{}", name, suffix))
+                .display()
+                .to_string()
+        };
+        Ok(Certificate {
+            cert_file: path(".crt"),
+            key_file: path(".key"),
+            ca_file: (!certificate.ca_public_key.is_empty()).then(|| path("-ca.crt")),
+        })
+    }
+}
+
+/// Writes each connection as `OPAMP_<NAME>_*` variables, with values double quoted so the file
+/// can be read as an `EnvironmentFile` or sourced by a shell
+fn render_env(connections: &BTreeMap<String, Connection>) -> Result<Vec<u8>, ApiClientError> {
+    let mut lines = vec![];
+    for (name, connection) in connections {
+        let prefix = format!("{}{}_", ENV_PREFIX, env_name(name));
+        lines.push((prefix.clone() + "ENDPOINT", connection.endpoint.clone()));
+        if !connection.headers.is_empty() {
+            let headers = connection
+                .headers
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect::<Vec<_>>()
+                .join(",");
+            lines.push((prefix.clone() + "HEADERS", headers));
+        }
+        if let Some(tls) = &connection.tls {
+            lines.push((prefix.clone() + "CERTIFICATE", tls.cert_file.clone()));
+            lines.push((prefix.clone() + "KEY", tls.key_file.clone()));
+            if let Some(ca_file) = &tls.ca_file {
+                lines.push((prefix.clone() + "CA_CERTIFICATE", ca_file.clone()));
+            }
+        }
+        for (key, value) in &connection.settings {
+            lines.push((prefix.clone() + env_name(key).as_str(), value.clone()));
+        }
+    }
+
+    let mut contents = String::new();
+    let mut keys = BTreeSet::new();
+    for (key, value) in lines {
+        if !keys.insert(key.clone()) {
+            return Err(ApiClientError::new(
+                line!(),
+                format!("Offered connections define {} more than once", key).as_str(),
+            ));
+        }
+        if value.contains(['\n', '\r']) {
+            return Err(ApiClientError::new(
+                line!(),
+                format!("Offered value of {} spans several lines", key).as_str(),
+            ));
+        }
+        contents.push_str(&format!("{}=\"{}\"\n", key, quote(&value)));
+    }
+    Ok(contents.into_bytes())
+}
+
+/// Escapes the characters that keep their meaning between double quotes
+fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '\\' | '"' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted
+}
+
+/// Writes the connections as a YAML map under `connections`
+fn render_yaml(connections: &BTreeMap<String, Connection>) -> Result<Vec<u8>, ApiClientError> {
+    let document = BTreeMap::from([("connections", connections)]);
+    serde_yaml::to_string(&document)
+        .map(String::into_bytes)
+        .map_err(|e| {
+            ApiClientError::new(
+                line!(),
+                format!("Unable to write other connections: {}", e).as_str(),
+            )
+        })
+}
+
+/// Upper cases a name and replaces anything that can't be part of a variable name
+fn env_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Replaces anything that can't be part of a file name
+fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection(endpoint: &str) -> Connection {
+        Connection {
+            endpoint: endpoint.to_string(),
+            headers: BTreeMap::new(),
+            tls: None,
+            settings: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn env_name_upper_cases_and_replaces() {
+        assert_eq!(env_name("traces"), "TRACES");
+        assert_eq!(env_name("my-backend.v2"), "MY_BACKEND_V2");
+        assert_eq!(env_name("ünï code"), "_N__CODE");
+    }
+
+    #[test]
+    fn render_env_quotes_values() {
+        let mut traces = connection("https://traces.example.com:4318");
+        traces.headers = BTreeMap::from([
+            ("Authorization".to_string(), "Bearer abc#1".to_string()),
+            ("X-Tenant".to_string(), "$HOME \"q\" `id` \\".to_string()),
+        ]);
+        traces.tls = Some(Certificate {
+            cert_file: "/certs/traces.crt".to_string(),
+            key_file: "/certs/traces.key".to_string(),
+            ca_file: None,
+        });
+        traces.settings = BTreeMap::from([("batch-size".to_string(), "512".to_string())]);
+        let connections = BTreeMap::from([("traces".to_string(), traces)]);
+
+        let contents = String::from_utf8(render_env(&connections).unwrap()).unwrap();
+        assert_eq!(
+            contents,
+            "OPAMP_TRACES_ENDPOINT=\"https://traces.example.com:4318\"\n\
+             OPAMP_TRACES_HEADERS=\"Authorization=Bearer abc#1,X-Tenant=\\$HOME \\\"q\\\" \\`id\\` \\\\\"\n\
+             OPAMP_TRACES_CERTIFICATE=\"/certs/traces.crt\"\n\
+             OPAMP_TRACES_KEY=\"/certs/traces.key\"\n\
+             OPAMP_TRACES_BATCH_SIZE=\"512\"\n"
+        );
+    }
+
+    #[test]
+    fn render_env_rejects_colliding_names() {
+        let connections = BTreeMap::from([
+            ("a-b".to_string(), connection("https://one")),
+            ("a_b".to_string(), connection("https://two")),
+        ]);
+        assert!(render_env(&connections).is_err());
+
+        let mut shadowing = connection("https://one");
+        shadowing.settings = BTreeMap::from([("endpoint".to_string(), "x".to_string())]);
+        let connections = BTreeMap::from([("a".to_string(), shadowing)]);
+        assert!(render_env(&connections).is_err());
+    }
+
+    #[test]
+    fn render_env_rejects_multi_line_values() {
+        let connections = BTreeMap::from([("a".to_string(), connection("https://one\nB=2"))]);
+        assert!(render_env(&connections).is_err());
+    }
+}
@@ -1,5 +1,7 @@
 use crossbeam_channel::bounded;
 use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
 use subprocess::{ Popen, PopenConfig, Redirection };
 
 /// These values are used to communicate with a subprocess launched by the `Task` struct.
@@ -19,17 +21,21 @@ pub enum Instruction {
 ///
 /// * `command`: A string that represents the command to be executed by the task.
 /// * `args`: `args` is a vector of strings that represents the arguments for the command that the
-/// `Task` struct is associated with. These arguments can be passed to the command when it is executed.
+///   `Task` struct is associated with. These arguments can be passed to the command when it is executed.
 /// * `control`: `control` is a `crossbeam_channel::Sender` that allows sending `Instruction` messages
-/// to control the task. This can be used to start, stop, pause, or resume the task, for example.
+///   to control the task. This can be used to start, stop, pause, or resume the task, for example.
 /// * `input`: `input` is a field of type `crossbeam_channel::Receiver<Instruction>`. It is a channel
-/// receiver that can receive messages of type `Instruction`. This field is used to receive instructions
-/// from the main thread or other tasks.
+///   receiver that can receive messages of type `Instruction`. This field is used to receive instructions
+///   from the main thread or other tasks.
+/// * `worker`: Handle of the thread managing the launched subprocess.
+/// * `child`: The launched subprocess, shared with the worker thread.
 pub struct Task {
     command: String,
     args: Vec<String>,
     pub control: crossbeam_channel::Sender<Instruction>,
     input: crossbeam_channel::Receiver<Instruction>,
+    worker: Mutex<Option<JoinHandle<bool>>>,
+    child: Arc<Mutex<Option<Popen>>>,
 }
 
 impl Task {
@@ -41,9 +47,50 @@ impl Task {
             args,
             control: command_tx,
             input: command_rx,
+            worker: Mutex::new(None),
+            child: Arc::new(Mutex::new(None)),
         }
     }
 
+    /// Whether the launched subprocess is still running
+    pub fn is_running(&self) -> bool {
+        self.child
+            .lock()
+            .unwrap()
+            .as_mut()
+            .is_some_and(|child| child.poll().is_none())
+    }
+
+    /// Exit status of the launched subprocess, once it has exited
+    pub fn exit_status(&self) -> Option<subprocess::ExitStatus> {
+        self.child.lock().unwrap().as_mut().and_then(|child| child.poll())
+    }
+
+    /// Gracefully stops the subprocess, if running, and launches it again. This blocks for
+    /// several seconds while the subprocess exits, async callers should use `spawn_blocking`
+    pub fn restart(&self) -> bool {
+        let worker = self.worker.lock().unwrap().take();
+        if let Some(worker) = worker {
+            if !worker.is_finished() {
+                if let Err(e) = self.control.send(Instruction::Exit) {
+                    log::warn!("Failed to request subprocess exit: {}", e);
+                }
+            }
+            if worker.join().is_err() {
+                log::warn!("Subprocess manager of {} panicked", &self.command);
+            }
+        }
+        // An instruction the previous worker did not pick up, such as an Exit sent while it was
+        // already finishing, must not reach the relaunched subprocess
+        let stale = self.input.try_iter().count();
+        if stale > 0 {
+            log::debug!("Discarded {} instructions for the previous subprocess", stale);
+        }
+
+        log::info!("Relaunching subtask {}", &self.command);
+        self.launch()
+    }
+
     pub fn launch(&self) -> bool {
         // Configure the Popen command
         let config = PopenConfig {
@@ -57,28 +104,65 @@ impl Task {
             .chain(self.args.iter())
             .cloned()
             .collect::<Vec<_>>();
-        let mut handle = Popen::create(&pcmd, config).expect("failed to spawn subprocess");
+        let mut popen = match Popen::create(&pcmd, config) {
+            Ok(handle) => handle,
+            Err(e) => {
+                log::error!("Failed to spawn subprocess {}: {}", &self.command, e);
+                return false;
+            }
+        };
+        // Get the subprocess's stdin handle
+        let mut stdin = match popen.stdin.take() {
+            Some(stdin) => stdin,
+            None => {
+                log::error!("Subprocess {} has no stdin pipe", &self.command);
+                return false;
+            }
+        };
+        *self.child.lock().unwrap() = Some(popen);
+        let child = self.child.clone();
         let input = self.input.clone();
         // Spawn a thread to manage the subprocess
-        std::thread::spawn(move || {
-            // Get the subprocess's stdin handle
-            let mut stdin = handle.stdin.take().unwrap();
+        let worker = std::thread::spawn(move || {
 
             // Create a buffer to read output from the subprocess
             // let mut stdout = BufReader::new(child.stdout.take().unwrap());
 
             // Read input from stdin and send it to the subprocess
             loop {
-                let instruction = input.recv().unwrap();
+                let instruction = match input.recv() {
+                    Ok(instruction) => instruction,
+                    Err(_) => {
+                        log::debug!("Control channel closed. Releasing subprocess");
+                        return false;
+                    }
+                };
                 log::debug!("Received instruction: {:#?}", &instruction);
                 match instruction {
                     Instruction::Input(input) => {
-                        stdin.write_all(input.as_bytes()).unwrap();
-                        stdin.flush().unwrap();
+                        if let Err(e) = stdin
+                            .write_all(input.as_bytes())
+                            .and_then(|_| stdin.flush())
+                        {
+                            log::warn!("Failed to write to subprocess: {}", e);
+                        }
                     }
                     Instruction::Exit => {
-                        stdin.write_all("exit\n".as_bytes()).unwrap();
-                        stdin.flush().unwrap();
+                        let exited = child
+                            .lock()
+                            .unwrap()
+                            .as_mut()
+                            .map_or(true, |handle| handle.poll().is_some());
+                        if exited {
+                            log::info!("Subprocess already exited");
+                            return true;
+                        }
+                        if let Err(e) = stdin
+                            .write_all("exit\n".as_bytes())
+                            .and_then(|_| stdin.flush())
+                        {
+                            log::warn!("Failed to request subprocess exit: {}", e);
+                        }
                         const WAIT_TIME: u64 = 5;
                         log::debug!(
                             "Waiting {} seconds for process to exit gracefully",
@@ -86,20 +170,34 @@ impl Task {
                         );
                         std::thread::sleep(std::time::Duration::from_secs(WAIT_TIME));
 
+                        // Not held while waiting above so `is_running` does not block
+                        let mut guard = child.lock().unwrap();
+                        let Some(handle) = guard.as_mut() else {
+                            return true;
+                        };
                         if
-                            let Some(_exit_status) = handle
-                                .wait_timeout(std::time::Duration::from_secs(1))
-                                .unwrap()
+                            let Ok(Some(_exit_status)) = handle.wait_timeout(
+                                std::time::Duration::from_secs(1)
+                            )
                         {
                             log::info!("Subprocess exited gracefully.");
                             return true;
                         } else {
                             log::warn!("Subprocess did not exit. Killing ...");
-                            handle.kill().unwrap();
+                            if let Err(e) = handle.kill() {
+                                log::error!("Failed to kill subprocess: {}", e);
+                            }
                         }
 
                         // Wait for the subprocess to finish and get its output
-                        match handle.wait().expect("failed to wait for agent subprocess") {
+                        let exit_status = match handle.wait() {
+                            Ok(exit_status) => exit_status,
+                            Err(e) => {
+                                log::error!("Failed to wait for agent subprocess: {}", e);
+                                return false;
+                            }
+                        };
+                        match exit_status {
                             subprocess::ExitStatus::Exited(status) => {
                                 if status != 0 {
                                     log::warn!(
@@ -120,13 +218,19 @@ impl Task {
                                     log::info!("Subprocess exited gracefully (signalled)");
                                 }
                             }
-                            subprocess::ExitStatus::Other(_) => todo!(),
-                            subprocess::ExitStatus::Undetermined => todo!(),
+                            subprocess::ExitStatus::Other(status) => {
+                                log::warn!("Subprocess exited with status: {}", status);
+                            }
+                            subprocess::ExitStatus::Undetermined => {
+                                log::warn!("Subprocess exit status undetermined");
+                            }
                         }
+                        return true;
                     }
                 }
             }
         });
+        *self.worker.lock().unwrap() = Some(worker);
         true
     }
 }
@@ -0,0 +1,475 @@
+//! Export of the records logged through the `log` facade to the destination offered in
+//! `ConnectionSettingsOffers.own_logs`.
+//!
+//! The [`OtlpLogger`] is installed as the global logger. It buffers records and hands them to an
+//! export task once the server offered a destination. The [`LogsExporter`] it returns is given to
+//! `Api::set_logs_exporter` so the client follows every new offer.
+
+use super::otlp::*;
+use crate::api::ApiClientError;
+use crate::description::attribute;
+use crate::opamp::spec::{any_value, AnyValue, KeyValue, TelemetryConnectionSettings};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Records kept while waiting for an export. The oldest records are dropped beyond that
+const BUFFER_CAPACITY: usize = 4096;
+
+/// Records sent in one export request
+const MAX_BATCH: usize = 512;
+
+/// Longest time a record waits in the buffer once a destination is known
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Modules of this crate never exported: this exporter and the OTLP client it exports with
+const EXCLUDED_MODULES: [&str; 2] = [module_path!(), super::otlp::TARGET];
+
+/// Crates never exported: the HTTP and TLS stack and the runtime exports run on, whose records
+/// would otherwise feed every export back into the buffer. Their companion crates, named after
+/// them with a suffix such as `hyper_util` or `tokio_rustls`, are excluded as well
+const EXCLUDED_CRATES: [&str; 14] = [
+    "reqwest",
+    "hyper",
+    "h2",
+    "http",
+    "want",
+    "tower",
+    "tokio",
+    "mio",
+    "native_tls",
+    "openssl",
+    "rustls",
+    "webpki",
+    "security_framework",
+    "schannel",
+];
+
+/// The `OtlpLogger` struct is a `log::Log` implementation exporting records as OTLP logs.
+///
+/// Properties:
+///
+/// * `shared`: Buffer and destination shared with the `LogsExporter`.
+/// * `inner`: Logger that also gets every record, e.g. to keep logging to the console.
+/// * `inner_level`: Most verbose level `inner` logs.
+/// * `level`: Most verbose level exported.
+pub struct OtlpLogger {
+    shared: Arc<Shared>,
+    inner: Option<Box<dyn Log>>,
+    inner_level: LevelFilter,
+    level: LevelFilter,
+}
+
+/// The `LogsExporter` struct configures the export of the records buffered by an `OtlpLogger`.
+///
+/// Properties:
+///
+/// * `shared`: Buffer and destination shared with the logger.
+#[derive(Clone)]
+pub struct LogsExporter {
+    shared: Arc<Shared>,
+}
+
+/// The `Shared` struct is the state of a logger and its exporter.
+///
+/// Properties:
+///
+/// * `buffer`: Records and export settings.
+/// * `notify`: Wakes the export task when a full batch is waiting.
+/// * `running`: Whether the export task was started.
+struct Shared {
+    buffer: Mutex<Buffer>,
+    notify: Notify,
+    running: AtomicBool,
+}
+
+/// The `Buffer` struct holds the records waiting for an export.
+///
+/// Properties:
+///
+/// * `records`: Records in the order they were logged.
+/// * `dropped`: Records lost so far, because the buffer was full or an export failed.
+/// * `unreported`: Records lost since the last export, reported with the next one.
+/// * `destination`: Where records are exported to, `None` until offered.
+/// * `resource`: Attributes identifying the agent.
+#[derive(Default)]
+struct Buffer {
+    records: VecDeque<LogRecord>,
+    dropped: u64,
+    unreported: u64,
+    destination: Option<Arc<Destination>>,
+    resource: Vec<KeyValue>,
+}
+
+impl OtlpLogger {
+    /// Creates a logger exporting records up to `level`
+    pub fn new(level: LevelFilter) -> OtlpLogger {
+        OtlpLogger {
+            shared: Arc::new(Shared {
+                buffer: Mutex::new(Buffer::default()),
+                notify: Notify::new(),
+                running: AtomicBool::new(false),
+            }),
+            inner: None,
+            inner_level: LevelFilter::Off,
+            level,
+        }
+    }
+
+    /// Passes every record up to `level` to `inner` as well
+    pub fn with_inner(mut self, inner: Box<dyn Log>, level: LevelFilter) -> Self {
+        self.inner = Some(inner);
+        self.inner_level = level;
+        self
+    }
+
+    /// Returns the exporter of this logger
+    pub fn exporter(&self) -> LogsExporter {
+        LogsExporter {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Installs the logger as the global logger and returns its exporter. The maximum level of
+    /// the `log` facade is set to the more verbose of the exported and the inner level.
+    pub fn init(self) -> Result<LogsExporter, ApiClientError> {
+        let exporter = self.exporter();
+        let level = self.level.max(self.inner_level);
+        log::set_boxed_logger(Box::new(self)).map_err(|e| {
+            ApiClientError::new(line!(), format!("Logger not installed: {}", e).as_str())
+        })?;
+        log::set_max_level(level);
+        Ok(exporter)
+    }
+
+    /// Whether a record is passed to the inner logger
+    fn inner_enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.inner_level
+            && self
+                .inner
+                .as_ref()
+                .is_some_and(|inner| inner.enabled(metadata))
+    }
+
+    /// Whether a record is exported
+    fn exports(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level && !excluded(metadata.target())
+    }
+}
+
+impl Log for OtlpLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level || self.inner_enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.inner_enabled(record.metadata()) {
+            if let Some(inner) = self.inner.as_ref() {
+                inner.log(record);
+            }
+        }
+        if !self.exports(record.metadata()) {
+            return;
+        }
+
+        let now = crate::get_time_nanos!() as u64;
+        let mut attributes = vec![attribute("code.namespace", record.target())];
+        if let Some(file) = record.file() {
+            attributes.push(attribute("code.filepath", file));
+        }
+        if let Some(line) = record.line() {
+            attributes.push(KeyValue {
+                key: "code.lineno".to_string(),
+                value: Some(AnyValue {
+                    value: Some(any_value::Value::IntValue(line as i64)),
+                }),
+            });
+        }
+        let batch_ready = self.shared.push(LogRecord {
+            time_unix_nano: now,
+            observed_time_unix_nano: now,
+            severity_number: severity(record.level()),
+            severity_text: record.level().to_string(),
+            body: Some(AnyValue {
+                value: Some(any_value::Value::StringValue(record.args().to_string())),
+            }),
+            attributes,
+        });
+        if batch_ready {
+            self.shared.notify.notify_one();
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(inner) = self.inner.as_ref() {
+            inner.flush();
+        }
+    }
+}
+
+impl LogsExporter {
+    /// Exports to the offered destination from now on. An offer without an endpoint stops the
+    /// export, records are buffered until the next offer.
+    pub(crate) fn configure(
+        &self,
+        offer: &TelemetryConnectionSettings,
+        resource: Vec<KeyValue>,
+    ) -> Result<(), ApiClientError> {
+        let destination = if offer.destination_endpoint.is_empty() {
+            log::info!("Own logs export stopped");
+            None
+        } else {
+            let destination = Destination::from_offer(offer)?;
+            log::info!("Exporting own logs to {}", destination.endpoint);
+            Some(Arc::new(destination))
+        };
+        {
+            let mut buffer = self.shared.buffer.lock().unwrap();
+            buffer.destination = destination;
+            buffer.resource = resource;
+        }
+
+        if !self.shared.running.swap(true, Ordering::SeqCst) {
+            tokio::spawn(export(self.shared.clone()));
+        }
+        Ok(())
+    }
+
+    /// Number of records lost so far, because the buffer was full or an export failed
+    pub fn dropped(&self) -> u64 {
+        self.shared.buffer.lock().unwrap().dropped
+    }
+
+    /// Exports the buffered records right away, e.g. before the process exits
+    pub async fn flush(&self) {
+        export_pending(&self.shared).await;
+    }
+}
+
+impl Shared {
+    /// Buffers a record, dropping the oldest one when full. Returns whether a batch is ready
+    fn push(&self, record: LogRecord) -> bool {
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer.records.len() >= BUFFER_CAPACITY {
+            buffer.records.pop_front();
+            buffer.lost(1);
+        }
+        buffer.records.push_back(record);
+        buffer.destination.is_some() && buffer.records.len() >= MAX_BATCH
+    }
+
+    /// Takes the next batch of records, along with a record about the ones lost since the last
+    /// export. Returns `None` when there is nothing to export or nowhere to export it to.
+    ///
+    /// Returns:
+    ///
+    /// The destination, the number of records taken, the number of lost records reported and
+    /// the request
+    fn take_batch(&self) -> Option<(Arc<Destination>, usize, u64, ExportLogsServiceRequest)> {
+        let mut buffer = self.buffer.lock().unwrap();
+        let destination = buffer.destination.clone()?;
+        if buffer.records.is_empty() && buffer.unreported == 0 {
+            return None;
+        }
+
+        let count = buffer.records.len().min(MAX_BATCH);
+        let mut log_records: Vec<LogRecord> = buffer.records.drain(..count).collect();
+        if buffer.unreported > 0 {
+            let now = crate::get_time_nanos!() as u64;
+            log_records.push(LogRecord {
+                time_unix_nano: now,
+                observed_time_unix_nano: now,
+                severity_number: severity(Level::Warn),
+                severity_text: Level::Warn.to_string(),
+                body: Some(AnyValue {
+                    value: Some(any_value::Value::StringValue(format!(
+                        "Dropped {} log records",
+                        buffer.unreported
+                    ))),
+                }),
+                attributes: vec![attribute("code.namespace", module_path!())],
+            });
+        }
+        let reported = buffer.unreported;
+
+        let request = ExportLogsServiceRequest {
+            resource_logs: vec![ResourceLogs {
+                resource: Some(Resource {
+                    attributes: buffer.resource.clone(),
+                    dropped_attributes_count: 0,
+                }),
+                scope_logs: vec![ScopeLogs {
+                    scope: Some(InstrumentationScope::this_crate()),
+                    log_records,
+                }],
+            }],
+        };
+        Some((destination, count, reported, request))
+    }
+}
+
+impl Buffer {
+    /// Counts lost records
+    fn lost(&mut self, count: u64) {
+        self.dropped += count;
+        self.unreported += count;
+    }
+}
+
+/// Exports whenever a batch is ready, and at least every `EXPORT_INTERVAL`
+async fn export(shared: Arc<Shared>) {
+    loop {
+        let _ = tokio::time::timeout(EXPORT_INTERVAL, shared.notify.notified()).await;
+        export_pending(&shared).await;
+    }
+}
+
+/// Exports batches until the buffer is empty or an export fails. Lost records stay unreported
+/// until an export carrying their count succeeds.
+async fn export_pending(shared: &Shared) {
+    while let Some((destination, count, reported, request)) = shared.take_batch() {
+        if let Err(e) = destination.export(&request).await {
+            // Logged records of this module are not exported, so this does not loop
+            log::debug!("Own logs: {}", e);
+            shared.buffer.lock().unwrap().lost(count as u64);
+            return;
+        }
+        let mut buffer = shared.buffer.lock().unwrap();
+        buffer.unreported = buffer.unreported.saturating_sub(reported);
+    }
+}
+
+/// Whether records of `target` are kept from being exported
+fn excluded(target: &str) -> bool {
+    let krate = target.split("::").next().unwrap_or(target);
+    EXCLUDED_CRATES.iter().any(|excluded| {
+        krate
+            .strip_prefix(excluded)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('_'))
+    }) || EXCLUDED_MODULES.iter().any(|excluded| {
+        target
+            .strip_prefix(excluded)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+    })
+}
+
+/// Maps a `log` level to an OTLP severity number
+fn severity(level: Level) -> i32 {
+    match level {
+        Level::Error => 17,
+        Level::Warn => 13,
+        Level::Info => 9,
+        Level::Debug => 5,
+        Level::Trace => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(body: &str) -> LogRecord {
+        LogRecord {
+            severity_number: severity(Level::Info),
+            body: Some(AnyValue {
+                value: Some(any_value::Value::StringValue(body.to_string())),
+            }),
+            ..LogRecord::default()
+        }
+    }
+
+    fn body(record: &LogRecord) -> &str {
+        match record.body.as_ref().and_then(|body| body.value.as_ref()) {
+            Some(any_value::Value::StringValue(body)) => body,
+            _ => "",
+        }
+    }
+
+    fn records(request: &ExportLogsServiceRequest) -> &[LogRecord] {
+        &request.resource_logs[0].scope_logs[0].log_records
+    }
+
+    /// Exports `logger` to a port nobody listens on
+    async fn export_unreachable(logger: &OtlpLogger) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/v1/logs", listener.local_addr().unwrap());
+        drop(listener);
+
+        let offer = TelemetryConnectionSettings {
+            destination_endpoint: endpoint,
+            ..TelemetryConnectionSettings::default()
+        };
+        logger.shared.buffer.lock().unwrap().destination =
+            Some(Arc::new(Destination::from_offer(&offer).unwrap()));
+    }
+
+    #[test]
+    fn excluded_covers_the_export_stack_and_its_companion_crates() {
+        for target in [
+            "hyper",
+            "hyper_util::client::legacy",
+            "rustls::conn",
+            "tokio_util::codec",
+            "h2::proto",
+            "reqwest::connect",
+            module_path!(),
+            super::super::otlp::TARGET,
+        ] {
+            assert!(excluded(target), "{}", target);
+        }
+        for target in ["mosfet_rs::session", "my_agent", "hyperion::core", "tokiox"] {
+            assert!(!excluded(target), "{}", target);
+        }
+    }
+
+    #[test]
+    fn push_drops_the_oldest_records_when_full() {
+        let logger = OtlpLogger::new(LevelFilter::Info);
+        for i in 0..BUFFER_CAPACITY + 3 {
+            logger.shared.push(record(&i.to_string()));
+        }
+
+        let exporter = logger.exporter();
+        assert_eq!(exporter.dropped(), 3);
+        let buffer = logger.shared.buffer.lock().unwrap();
+        assert_eq!(buffer.records.len(), BUFFER_CAPACITY);
+        assert_eq!(body(&buffer.records[0]), "3");
+        assert_eq!(buffer.unreported, 3);
+    }
+
+    #[tokio::test]
+    async fn take_batch_waits_for_a_destination_and_reports_drops() {
+        let logger = OtlpLogger::new(LevelFilter::Info);
+        for i in 0..BUFFER_CAPACITY + 3 {
+            logger.shared.push(record(&i.to_string()));
+        }
+        assert!(logger.shared.take_batch().is_none());
+
+        export_unreachable(&logger).await;
+        let (_, count, reported, request) = logger.shared.take_batch().unwrap();
+        assert_eq!((count, reported), (MAX_BATCH, 3));
+        assert_eq!(records(&request).len(), MAX_BATCH + 1);
+        assert_eq!(
+            body(records(&request).last().unwrap()),
+            "Dropped 3 log records"
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_export_is_reported_with_the_next_one() {
+        let logger = OtlpLogger::new(LevelFilter::Info);
+        export_unreachable(&logger).await;
+        for i in 0..10 {
+            logger.shared.push(record(&i.to_string()));
+        }
+
+        export_pending(&logger.shared).await;
+        assert_eq!(logger.exporter().dropped(), 10);
+        let (_, count, reported, request) = logger.shared.take_batch().unwrap();
+        assert_eq!((count, reported), (0, 10));
+        assert_eq!(body(&records(&request)[0]), "Dropped 10 log records");
+    }
+}
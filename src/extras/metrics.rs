@@ -0,0 +1,320 @@
+//! Export of the supervisor's own metrics to the destination offered in
+//! `ConnectionSettingsOffers.own_metrics`.
+
+use super::otlp::{metric::Data, number_data_point::Value, *};
+use crate::api::ApiClientError;
+use crate::opamp::spec::{KeyValue, TelemetryConnectionSettings};
+use crate::state::ConnectionStatus;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use sysinfo::{Pid, ProcessExt, System, SystemExt};
+
+/// Period between two exports
+const EXPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The `MetricsExporter` struct exports the connection metrics of the client and the CPU and
+/// memory use of the process over OTLP/HTTP. Exporting starts with the first offer and follows
+/// every new one.
+///
+/// Properties:
+///
+/// * `shared`: State shared with the export task.
+/// * `running`: Whether the export task was started.
+pub(crate) struct MetricsExporter {
+    shared: Arc<Mutex<Shared>>,
+    running: bool,
+}
+
+/// The `Shared` struct holds what the export task needs from the session.
+///
+/// Properties:
+///
+/// * `destination`: Where metrics are exported to, `None` until offered.
+/// * `resource`: Attributes identifying the agent.
+/// * `status`: The latest connection status recorded by the session.
+#[derive(Default)]
+struct Shared {
+    destination: Option<Arc<Destination>>,
+    resource: Vec<KeyValue>,
+    status: Option<ConnectionStatus>,
+}
+
+impl MetricsExporter {
+    pub(crate) fn new() -> MetricsExporter {
+        MetricsExporter {
+            shared: Arc::new(Mutex::new(Shared::default())),
+            running: false,
+        }
+    }
+
+    /// Exports to the offered destination from now on. An offer without an endpoint stops the
+    /// export.
+    pub(crate) fn configure(
+        &mut self,
+        offer: &TelemetryConnectionSettings,
+        resource: Vec<KeyValue>,
+    ) -> Result<(), ApiClientError> {
+        let destination = if offer.destination_endpoint.is_empty() {
+            log::info!("Own metrics export stopped");
+            None
+        } else {
+            let destination = Destination::from_offer(offer)?;
+            log::info!("Exporting own metrics to {}", destination.endpoint);
+            Some(Arc::new(destination))
+        };
+        {
+            let mut shared = self.shared.lock().unwrap();
+            shared.destination = destination;
+            shared.resource = resource;
+        }
+
+        if !self.running {
+            tokio::spawn(export(Arc::downgrade(&self.shared)));
+            self.running = true;
+        }
+        Ok(())
+    }
+
+    /// Records the latest connection status for the next export
+    pub(crate) fn record(&self, status: ConnectionStatus) {
+        self.shared.lock().unwrap().status = Some(status);
+    }
+}
+
+/// Exports metrics every `EXPORT_INTERVAL` until the exporter is dropped
+async fn export(shared: Weak<Mutex<Shared>>) {
+    let start_time = crate::get_time_nanos!() as u64;
+    let pid = sysinfo::get_current_pid().ok();
+    let mut sys = System::new();
+    // sysinfo measures the CPU usage between two refreshes. Take the first sample right away so
+    // the first export reports the usage since then rather than none
+    if let Some(pid) = pid {
+        sys.refresh_process(pid);
+    }
+
+    loop {
+        tokio::time::sleep(EXPORT_INTERVAL).await;
+        let (destination, resource, status) = match shared.upgrade() {
+            Some(shared) => {
+                let shared = shared.lock().unwrap();
+                (
+                    shared.destination.clone(),
+                    shared.resource.clone(),
+                    shared.status.clone(),
+                )
+            }
+            None => return,
+        };
+        let (Some(destination), Some(status)) = (destination, status) else {
+            continue;
+        };
+
+        let metrics = collect(&status, process_usage(&mut sys, pid), start_time);
+        let request = ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: Some(Resource {
+                    attributes: resource,
+                    dropped_attributes_count: 0,
+                }),
+                scope_metrics: vec![ScopeMetrics {
+                    scope: Some(InstrumentationScope::this_crate()),
+                    metrics,
+                }],
+            }],
+        };
+        // Kept at debug level so a broken destination does not flood the logs it may be
+        // shipping as well
+        if let Err(e) = destination.export(&request).await {
+            log::debug!("Own metrics: {}", e);
+        }
+    }
+}
+
+/// Reports the (CPU utilization, resident memory, virtual memory) of this process. sysinfo
+/// reports the CPU usage as a percentage of one CPU, the utilization is spread over the CPUs
+/// available to the process.
+fn process_usage(sys: &mut System, pid: Option<Pid>) -> Option<(f32, u64, u64)> {
+    let pid = pid.filter(|pid| sys.refresh_process(*pid))?;
+    let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+    sys.process(pid).map(|process| {
+        (
+            process.cpu_usage() / 100.0 / cpus as f32,
+            process.memory(),
+            process.virtual_memory(),
+        )
+    })
+}
+
+/// Builds the metrics of one export
+fn collect(
+    status: &ConnectionStatus,
+    process: Option<(f32, u64, u64)>,
+    start_time: u64,
+) -> Vec<Metric> {
+    let now = crate::get_time_nanos!() as u64;
+    let point = |value: Value, start_time_unix_nano: u64| NumberDataPoint {
+        attributes: vec![],
+        start_time_unix_nano,
+        time_unix_nano: now,
+        value: Some(value),
+    };
+    let gauge = |name: &str, description: &str, unit: &str, value: Value| Metric {
+        name: name.to_string(),
+        description: description.to_string(),
+        unit: unit.to_string(),
+        data: Some(Data::Gauge(Gauge {
+            data_points: vec![point(value, 0)],
+        })),
+    };
+    let counter = |name: &str, description: &str, unit: &str, value: u64| Metric {
+        name: name.to_string(),
+        description: description.to_string(),
+        unit: unit.to_string(),
+        data: Some(Data::Sum(Sum {
+            data_points: vec![point(Value::AsInt(value as i64), start_time)],
+            aggregation_temporality: CUMULATIVE,
+            is_monotonic: true,
+        })),
+    };
+
+    let mut metrics = vec![
+        gauge(
+            "opamp.client.connected",
+            "Whether the client is connected to the OpAMP server",
+            "1",
+            Value::AsInt(status.state.is_connected() as i64),
+        ),
+        counter(
+            "opamp.client.messages.sent",
+            "Messages sent to the OpAMP server",
+            "{message}",
+            status.messages_sent,
+        ),
+        counter(
+            "opamp.client.messages.received",
+            "Messages received from the OpAMP server",
+            "{message}",
+            status.messages_received,
+        ),
+        counter(
+            "opamp.client.errors",
+            "Connection, transport and server errors",
+            "{error}",
+            status.errors,
+        ),
+        gauge(
+            "opamp.client.retries",
+            "Consecutive failed connection attempts",
+            "{attempt}",
+            Value::AsInt(status.retries as i64),
+        ),
+    ];
+    if let Some((cpu, memory, virtual_memory)) = process {
+        metrics.push(gauge(
+            "process.cpu.utilization",
+            "CPU time used by the process per wall clock time and CPU",
+            "1",
+            Value::AsDouble(cpu as f64),
+        ));
+        metrics.push(gauge(
+            "process.memory.usage",
+            "Physical memory used by the process",
+            "By",
+            Value::AsInt(memory as i64),
+        ));
+        metrics.push(gauge(
+            "process.memory.virtual",
+            "Virtual memory used by the process",
+            "By",
+            Value::AsInt(virtual_memory as i64),
+        ));
+    }
+    metrics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::State;
+
+    fn status(state: State) -> ConnectionStatus {
+        ConnectionStatus {
+            state,
+            last_error: None,
+            last_exchange: None,
+            sequence_num: 7,
+            retries: 2,
+            messages_sent: 5,
+            messages_received: 4,
+            errors: 1,
+        }
+    }
+
+    fn value(metrics: &[Metric], name: &str) -> Option<(Value, u64)> {
+        let metric = metrics.iter().find(|metric| metric.name == name)?;
+        let point = match metric.data.as_ref()? {
+            Data::Gauge(gauge) => &gauge.data_points[0],
+            Data::Sum(sum) => &sum.data_points[0],
+        };
+        Some((point.value.clone()?, point.start_time_unix_nano))
+    }
+
+    #[test]
+    fn collect_reports_the_connection_status() {
+        let metrics = collect(&status(State::Connected("".to_string())), None, 42);
+
+        assert_eq!(
+            value(&metrics, "opamp.client.connected"),
+            Some((Value::AsInt(1), 0))
+        );
+        assert_eq!(
+            value(&metrics, "opamp.client.messages.sent"),
+            Some((Value::AsInt(5), 42))
+        );
+        assert_eq!(
+            value(&metrics, "opamp.client.messages.received"),
+            Some((Value::AsInt(4), 42))
+        );
+        assert_eq!(
+            value(&metrics, "opamp.client.errors"),
+            Some((Value::AsInt(1), 42))
+        );
+        assert_eq!(
+            value(&metrics, "opamp.client.retries"),
+            Some((Value::AsInt(2), 0))
+        );
+        assert!(value(&metrics, "process.cpu.utilization").is_none());
+    }
+
+    #[test]
+    fn collect_reports_the_process_usage_when_known() {
+        let disconnected = status(State::Disconnected("".to_string()));
+        let metrics = collect(&disconnected, Some((0.25, 1024, 4096)), 42);
+
+        assert_eq!(
+            value(&metrics, "opamp.client.connected"),
+            Some((Value::AsInt(0), 0))
+        );
+        assert_eq!(
+            value(&metrics, "process.cpu.utilization"),
+            Some((Value::AsDouble(0.25), 0))
+        );
+        assert_eq!(
+            value(&metrics, "process.memory.usage"),
+            Some((Value::AsInt(1024), 0))
+        );
+        assert_eq!(
+            value(&metrics, "process.memory.virtual"),
+            Some((Value::AsInt(4096), 0))
+        );
+    }
+
+    #[test]
+    fn process_usage_reports_this_process() {
+        let mut sys = System::new();
+        let pid = sysinfo::get_current_pid().ok();
+        let (cpu, memory, _) = process_usage(&mut sys, pid).unwrap();
+        assert!((0.0..=1.0).contains(&cpu), "{}", cpu);
+        assert!(memory > 0);
+    }
+}
@@ -1,5 +1,20 @@
 #[cfg(feature = "config")]
 pub mod config;
 
+#[cfg(feature = "connections")]
+pub mod connections;
+
 #[cfg(feature = "launcher")]
 pub mod launcher;
+
+#[cfg(feature = "packages")]
+pub mod packages;
+
+#[cfg(feature = "metrics")]
+pub(crate) mod metrics;
+
+#[cfg(feature = "logs")]
+pub mod logs;
+
+#[cfg(any(feature = "metrics", feature = "logs"))]
+pub(crate) mod otlp;
@@ -0,0 +1,265 @@
+//! The parts of the OTLP/HTTP protobuf protocol used to export the supervisor's own telemetry
+//! to the destinations offered by the server.
+
+use crate::api::ApiClientError;
+use crate::opamp::spec::{KeyValue, TelemetryConnectionSettings};
+use prost::Message;
+use reqwest::Client as ReqwestClient;
+
+/// Log target of this module
+#[cfg(feature = "logs")]
+pub(crate) const TARGET: &str = module_path!();
+
+/// The `Destination` struct is an OTLP/HTTP endpoint offered in `TelemetryConnectionSettings`.
+///
+/// Properties:
+///
+/// * `endpoint`: Full URL of the OTLP receiver, including the signal path.
+/// * `headers`: Headers sent with every export.
+/// * `client`: HTTP client presenting the offered client certificate, if any.
+pub(crate) struct Destination {
+    pub(crate) endpoint: url::Url,
+    headers: Vec<(String, String)>,
+    client: ReqwestClient,
+}
+
+impl Destination {
+    /// Checks an offer and prepares a client for it
+    pub(crate) fn from_offer(
+        offer: &TelemetryConnectionSettings,
+    ) -> Result<Destination, ApiClientError> {
+        let endpoint = url::Url::parse(&offer.destination_endpoint)
+            .ok()
+            .filter(|endpoint| matches!(endpoint.scheme(), "http" | "https"))
+            .ok_or_else(|| {
+                ApiClientError::new(
+                    line!(),
+                    format!(
+                        "Invalid telemetry destination {}",
+                        offer.destination_endpoint
+                    )
+                    .as_str(),
+                )
+            })?;
+        let headers = offer
+            .headers
+            .iter()
+            .flat_map(|headers| &headers.headers)
+            .map(|header| (header.key.clone(), header.value.clone()))
+            .collect();
+        let client = match &offer.certificate {
+            Some(certificate) => ReqwestClient::builder()
+                .use_preconfigured_tls(crate::tls::connector(Some(certificate))?)
+                .build()
+                .map_err(|e| {
+                    ApiClientError::new(line!(), format!("HTTP client failed: {}", e).as_str())
+                })?,
+            None => ReqwestClient::new(),
+        };
+
+        Ok(Destination {
+            endpoint,
+            headers,
+            client,
+        })
+    }
+
+    /// Posts an OTLP export request
+    pub(crate) async fn export(&self, request: &impl Message) -> Result<(), ApiClientError> {
+        let mut post = self
+            .client
+            .post(self.endpoint.clone())
+            .header("Content-Type", "application/x-protobuf")
+            .body(request.encode_to_vec());
+        for (key, value) in &self.headers {
+            post = post.header(key, value);
+        }
+
+        let response = post.send().await.map_err(|e| {
+            ApiClientError::new(line!(), format!("OTLP export failed: {}", e).as_str())
+        })?;
+        if !response.status().is_success() {
+            return Err(ApiClientError::new(
+                line!(),
+                format!("OTLP export failed: {}", response.status()).as_str(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// opentelemetry.proto.resource.v1.Resource
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct Resource {
+    #[prost(message, repeated, tag = "1")]
+    pub attributes: Vec<KeyValue>,
+    #[prost(uint32, tag = "2")]
+    pub dropped_attributes_count: u32,
+}
+
+/// opentelemetry.proto.common.v1.InstrumentationScope
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct InstrumentationScope {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub version: String,
+}
+
+impl InstrumentationScope {
+    /// The scope of everything this crate exports
+    pub(crate) fn this_crate() -> InstrumentationScope {
+        InstrumentationScope {
+            name: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
+/// opentelemetry.proto.collector.metrics.v1.ExportMetricsServiceRequest
+#[cfg(feature = "metrics")]
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct ExportMetricsServiceRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub resource_metrics: Vec<ResourceMetrics>,
+}
+
+/// opentelemetry.proto.metrics.v1.ResourceMetrics
+#[cfg(feature = "metrics")]
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct ResourceMetrics {
+    #[prost(message, optional, tag = "1")]
+    pub resource: Option<Resource>,
+    #[prost(message, repeated, tag = "2")]
+    pub scope_metrics: Vec<ScopeMetrics>,
+}
+
+/// opentelemetry.proto.metrics.v1.ScopeMetrics
+#[cfg(feature = "metrics")]
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct ScopeMetrics {
+    #[prost(message, optional, tag = "1")]
+    pub scope: Option<InstrumentationScope>,
+    #[prost(message, repeated, tag = "2")]
+    pub metrics: Vec<Metric>,
+}
+
+/// opentelemetry.proto.metrics.v1.Metric, limited to gauges and sums
+#[cfg(feature = "metrics")]
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct Metric {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub description: String,
+    #[prost(string, tag = "3")]
+    pub unit: String,
+    #[prost(oneof = "metric::Data", tags = "5, 7")]
+    pub data: Option<metric::Data>,
+}
+
+#[cfg(feature = "metrics")]
+pub(crate) mod metric {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub(crate) enum Data {
+        #[prost(message, tag = "5")]
+        Gauge(super::Gauge),
+        #[prost(message, tag = "7")]
+        Sum(super::Sum),
+    }
+}
+
+/// opentelemetry.proto.metrics.v1.Gauge
+#[cfg(feature = "metrics")]
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct Gauge {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<NumberDataPoint>,
+}
+
+/// opentelemetry.proto.metrics.v1.Sum
+#[cfg(feature = "metrics")]
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct Sum {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<NumberDataPoint>,
+    #[prost(int32, tag = "2")]
+    pub aggregation_temporality: i32,
+    #[prost(bool, tag = "3")]
+    pub is_monotonic: bool,
+}
+
+/// AGGREGATION_TEMPORALITY_CUMULATIVE
+#[cfg(feature = "metrics")]
+pub(crate) const CUMULATIVE: i32 = 2;
+
+/// opentelemetry.proto.metrics.v1.NumberDataPoint
+#[cfg(feature = "metrics")]
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct NumberDataPoint {
+    #[prost(message, repeated, tag = "7")]
+    pub attributes: Vec<KeyValue>,
+    #[prost(fixed64, tag = "2")]
+    pub start_time_unix_nano: u64,
+    #[prost(fixed64, tag = "3")]
+    pub time_unix_nano: u64,
+    #[prost(oneof = "number_data_point::Value", tags = "4, 6")]
+    pub value: Option<number_data_point::Value>,
+}
+
+#[cfg(feature = "metrics")]
+pub(crate) mod number_data_point {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub(crate) enum Value {
+        #[prost(double, tag = "4")]
+        AsDouble(f64),
+        #[prost(sfixed64, tag = "6")]
+        AsInt(i64),
+    }
+}
+
+/// opentelemetry.proto.collector.logs.v1.ExportLogsServiceRequest
+#[cfg(feature = "logs")]
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct ExportLogsServiceRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub resource_logs: Vec<ResourceLogs>,
+}
+
+/// opentelemetry.proto.logs.v1.ResourceLogs
+#[cfg(feature = "logs")]
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct ResourceLogs {
+    #[prost(message, optional, tag = "1")]
+    pub resource: Option<Resource>,
+    #[prost(message, repeated, tag = "2")]
+    pub scope_logs: Vec<ScopeLogs>,
+}
+
+/// opentelemetry.proto.logs.v1.ScopeLogs
+#[cfg(feature = "logs")]
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct ScopeLogs {
+    #[prost(message, optional, tag = "1")]
+    pub scope: Option<InstrumentationScope>,
+    #[prost(message, repeated, tag = "2")]
+    pub log_records: Vec<LogRecord>,
+}
+
+/// opentelemetry.proto.logs.v1.LogRecord, without trace context
+#[cfg(feature = "logs")]
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct LogRecord {
+    #[prost(fixed64, tag = "1")]
+    pub time_unix_nano: u64,
+    #[prost(fixed64, tag = "11")]
+    pub observed_time_unix_nano: u64,
+    #[prost(int32, tag = "2")]
+    pub severity_number: i32,
+    #[prost(string, tag = "3")]
+    pub severity_text: String,
+    #[prost(message, optional, tag = "5")]
+    pub body: Option<crate::opamp::spec::AnyValue>,
+    #[prost(message, repeated, tag = "6")]
+    pub attributes: Vec<KeyValue>,
+}
@@ -0,0 +1,700 @@
+use crate::api::ApiClientError;
+use crate::opamp::spec::*;
+use flate2::read::GzDecoder;
+use reqwest::{header::RANGE, Client as ReqwestClient, StatusCode};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+/// Name of the link (or pointer file on platforms without symlinks) selecting the active version
+const CURRENT: &str = "current";
+
+/// Directory holding partial downloads so they can be resumed
+const DOWNLOADS: &str = ".downloads";
+
+/// Time allowed for connecting to a download server
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time allowed for a whole download. A download that runs out of time resumes on the next offer
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// The `PackageManager` struct installs the packages offered by the server in `PackagesAvailable`.
+///
+/// Each package is downloaded (resuming an earlier partial download when the server supports
+/// ranges), verified against `content_hash`, and unpacked into its own versioned directory below
+/// `root/<package name>`. The `current` entry next to the versions is then switched atomically to
+/// the new version. Gzipped tarballs and plain tarballs are unpacked, any other file is installed
+/// as is. Files offered without a `content_hash` are refused unless unverified downloads were
+/// allowed.
+///
+/// Properties:
+///
+/// * `root`: Directory packages are installed under.
+/// * `client`: HTTP client used for downloads.
+/// * `statuses`: The `PackageStatuses` reported to the server.
+/// * `offered`: The packages most recently offered by the server, by name.
+/// * `pending`: Names of offered packages that still need to be installed.
+/// * `withdrawn`: Names of packages no longer offered whose files still need to be removed.
+/// * `allow_unverified`: Whether files offered without a content hash are installed.
+pub struct PackageManager {
+    root: PathBuf,
+    client: ReqwestClient,
+    statuses: PackageStatuses,
+    offered: HashMap<String, PackageAvailable>,
+    pending: VecDeque<String>,
+    withdrawn: VecDeque<String>,
+    allow_unverified: bool,
+}
+
+impl PackageManager {
+    pub fn new(root: impl Into<PathBuf>) -> PackageManager {
+        PackageManager {
+            root: root.into(),
+            client: download_client(CONNECT_TIMEOUT, DOWNLOAD_TIMEOUT),
+            statuses: PackageStatuses::default(),
+            offered: HashMap::new(),
+            pending: VecDeque::new(),
+            withdrawn: VecDeque::new(),
+            allow_unverified: false,
+        }
+    }
+
+    /// Installs files the server offers without a content hash as well. Their contents can not
+    /// be checked, so only use this with servers that never send one.
+    pub fn with_unverified_downloads(mut self) -> Self {
+        self.allow_unverified = true;
+        self
+    }
+
+    /// Limits the time spent connecting to a download server and the time a whole download may
+    /// take, 30 seconds and 30 minutes by default
+    pub fn with_timeouts(mut self, connect: Duration, download: Duration) -> Self {
+        self.client = download_client(connect, download);
+        self
+    }
+
+    /// Reports the status of every package known to the manager
+    pub fn statuses(&self) -> &PackageStatuses {
+        &self.statuses
+    }
+
+    /// Replaces the known statuses, e.g. with those saved by a previous run
+    pub fn restore(&mut self, statuses: PackageStatuses) {
+        self.statuses = statuses;
+    }
+
+    /// Path of the active version of package `name`
+    pub fn current_dir(&self, name: &str) -> PathBuf {
+        self.root.join(name).join(CURRENT)
+    }
+
+    /// Whether offered packages are still waiting to be installed
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Records a new offer from the server. Packages whose hash differs from the installed one
+    /// are queued for installation and reported as installing. Packages missing from the offer
+    /// are dropped from the statuses and queued for removal.
+    ///
+    /// Returns:
+    ///
+    /// The updated `PackageStatuses` when the offer changed anything, `None` otherwise.
+    pub fn offer(&mut self, available: &PackagesAvailable) -> Option<PackageStatuses> {
+        if !available.all_packages_hash.is_empty()
+            && available.all_packages_hash == self.statuses.server_provided_all_packages_hash
+            && !self.has_failures()
+        {
+            log::debug!("Package offer unchanged. Skipping");
+            return None;
+        }
+
+        self.statuses.server_provided_all_packages_hash = available.all_packages_hash.clone();
+        self.statuses.error_message = "".to_string();
+        self.offered = available.packages.clone();
+        self.pending.clear();
+
+        let withdrawn: Vec<String> = self
+            .statuses
+            .packages
+            .keys()
+            .filter(|name| !available.packages.contains_key(*name))
+            .cloned()
+            .collect();
+        for name in withdrawn {
+            log::info!("Package {} is no longer offered", name);
+            self.statuses.packages.remove(&name);
+            self.withdrawn.push_back(name);
+        }
+
+        for (name, package) in &available.packages {
+            self.withdrawn.retain(|withdrawn| withdrawn != name);
+            let status = self
+                .statuses
+                .packages
+                .entry(name.clone())
+                .or_insert_with(|| PackageStatus {
+                    name: name.clone(),
+                    ..PackageStatus::default()
+                });
+            status.server_offered_version = package.version.clone();
+            status.server_offered_hash = package.hash.clone();
+
+            if status.agent_has_hash == package.hash
+                && status.status == PackageStatusEnum::Installed as i32
+            {
+                continue;
+            }
+            status.status = PackageStatusEnum::Installing.into();
+            status.error_message = "".to_string();
+            self.pending.push_back(name.clone());
+        }
+
+        Some(self.statuses.clone())
+    }
+
+    /// Removes the files of withdrawn packages, then installs the next pending package and
+    /// records the outcome
+    ///
+    /// Returns:
+    ///
+    /// The updated `PackageStatuses`, or `None` if nothing was pending.
+    pub async fn install_next(&mut self) -> Option<PackageStatuses> {
+        while let Some(name) = self.withdrawn.pop_front() {
+            if let Err(e) = self.uninstall(&name).await {
+                log::warn!("Package {} could not be removed: {}", name, e);
+            }
+        }
+
+        let name = self.pending.pop_front()?;
+        let package = self.offered.get(&name)?.clone();
+
+        log::info!("Installing package {} {}", name, package.version);
+        let outcome = self.install(&name, &package).await;
+
+        let status = self.statuses.packages.get_mut(&name)?;
+        match outcome {
+            Ok(()) => {
+                log::info!("Installed package {} {}", name, package.version);
+                status.agent_has_version = package.version.clone();
+                status.agent_has_hash = package.hash.clone();
+                status.status = PackageStatusEnum::Installed.into();
+                status.error_message = "".to_string();
+            }
+            Err(e) => {
+                log::warn!("Package {} failed to install: {}", name, e);
+                status.status = PackageStatusEnum::InstallFailed.into();
+                status.error_message = e.to_string();
+            }
+        }
+        Some(self.statuses.clone())
+    }
+
+    fn has_failures(&self) -> bool {
+        self.statuses
+            .packages
+            .values()
+            .any(|status| status.status == PackageStatusEnum::InstallFailed as i32)
+    }
+
+    /// Removes every installed version of a package
+    async fn uninstall(&self, name: &str) -> Result<(), ApiClientError> {
+        let package_dir = self.root.join(safe_name(name)?);
+        blocking(move || match fs::remove_dir_all(&package_dir) {
+            Ok(()) => {
+                log::info!("Removed {}", package_dir.display());
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(io_error(line!(), &package_dir, e)),
+        })
+        .await
+    }
+
+    /// Downloads, verifies, unpacks and activates a single package
+    async fn install(&self, name: &str, package: &PackageAvailable) -> Result<(), ApiClientError> {
+        let file = package
+            .file
+            .as_ref()
+            .ok_or_else(|| ApiClientError::new(line!(), "Package has no downloadable file"))?;
+        let package_dir = self.root.join(safe_name(name)?);
+        let downloads = package_dir.join(DOWNLOADS);
+        tokio::fs::create_dir_all(&downloads)
+            .await
+            .map_err(|e| io_error(line!(), &downloads, e))?;
+
+        let version = if !package.version.is_empty() {
+            safe_name(&package.version)?.to_string()
+        } else if !package.hash.is_empty() {
+            to_hex(&package.hash)
+        } else {
+            return Err(ApiClientError::new(
+                line!(),
+                "Package has no version or hash",
+            ));
+        };
+        let partial = downloads.join(format!("{}.part", version));
+
+        self.download(file, &partial).await?;
+
+        let content_hash = file.content_hash.clone();
+        let url = file.download_url.clone();
+        let allow_unverified = self.allow_unverified;
+        blocking(move || {
+            if let Err(e) = verify(&partial, &content_hash, allow_unverified) {
+                // Start from scratch next time rather than resuming a corrupt download
+                let _ = fs::remove_file(&partial);
+                return Err(e);
+            }
+            activate(&package_dir, &version, &partial, &url)?;
+            let _ = fs::remove_file(&partial);
+            Ok(())
+        })
+        .await
+    }
+
+    /// Streams `file` into `destination`, resuming from the bytes already present
+    async fn download(
+        &self,
+        file: &DownloadableFile,
+        destination: &Path,
+    ) -> Result<(), ApiClientError> {
+        let offset = tokio::fs::metadata(destination)
+            .await
+            .map(|m| m.len())
+            .unwrap_or(0);
+
+        let mut request = self.client.get(&file.download_url);
+        if let Some(headers) = &file.headers {
+            for header in &headers.headers {
+                request = request.header(&header.key, &header.value);
+            }
+        }
+        if offset > 0 {
+            log::debug!(
+                "Resuming download of {} at {} bytes",
+                file.download_url,
+                offset
+            );
+            request = request.header(RANGE, format!("bytes={}-", offset));
+        }
+
+        let mut response = request.send().await.map_err(|e| {
+            ApiClientError::new(line!(), format!("Download failed: {}", e).as_str())
+        })?;
+        let resume = match response.status() {
+            StatusCode::PARTIAL_CONTENT => true,
+            // The partial file already holds everything
+            StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => return Ok(()),
+            status if status.is_success() => false,
+            status => {
+                return Err(ApiClientError::new(
+                    line!(),
+                    format!("Download failed: {}", status).as_str(),
+                ))
+            }
+        };
+
+        let mut out = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(resume)
+            .truncate(!resume)
+            .open(destination)
+            .await
+            .map_err(|e| io_error(line!(), destination, e))?;
+        while let Some(chunk) = response.chunk().await.map_err(|e| {
+            ApiClientError::new(line!(), format!("Download interrupted: {}", e).as_str())
+        })? {
+            out.write_all(&chunk)
+                .await
+                .map_err(|e| io_error(line!(), destination, e))?;
+        }
+        out.flush()
+            .await
+            .map_err(|e| io_error(line!(), destination, e))?;
+        Ok(())
+    }
+}
+
+/// Builds the HTTP client used for downloads
+fn download_client(connect: Duration, download: Duration) -> ReqwestClient {
+    ReqwestClient::builder()
+        .connect_timeout(connect)
+        .timeout(download)
+        .build()
+        .unwrap_or_else(|e| {
+            log::warn!("Unable to apply download timeouts: {}", e);
+            ReqwestClient::new()
+        })
+}
+
+/// Runs blocking file system work off the async runtime
+async fn blocking(
+    task: impl FnOnce() -> Result<(), ApiClientError> + Send + 'static,
+) -> Result<(), ApiClientError> {
+    tokio::task::spawn_blocking(task)
+        .await
+        .map_err(|e| ApiClientError::new(line!(), format!("Package task failed: {}", e).as_str()))?
+}
+
+/// Checks the SHA256 of a downloaded file against the hash the server sent. A file without a
+/// hash only passes when `allow_unverified` is set.
+fn verify(path: &Path, content_hash: &[u8], allow_unverified: bool) -> Result<(), ApiClientError> {
+    if content_hash.is_empty() {
+        if !allow_unverified {
+            return Err(ApiClientError::new(
+                line!(),
+                "Package file has no content hash",
+            ));
+        }
+        log::warn!(
+            "No content hash for {}. Skipping verification",
+            path.display()
+        );
+        return Ok(());
+    }
+
+    let mut file = File::open(path).map_err(|e| io_error(line!(), path, e))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).map_err(|e| io_error(line!(), path, e))?;
+    let digest = hasher.finalize();
+    if digest.as_slice() != content_hash {
+        return Err(ApiClientError::new(
+            line!(),
+            format!(
+                "Content hash mismatch: expected {}, got {}",
+                to_hex(content_hash),
+                to_hex(&digest)
+            )
+            .as_str(),
+        ));
+    }
+    Ok(())
+}
+
+/// Unpacks a verified download next to the installed versions and makes it the current one. A
+/// version installed before under the same name is moved aside and only removed once the new
+/// one is in place, or moved back if that fails.
+fn activate(
+    package_dir: &Path,
+    version: &str,
+    archive: &Path,
+    url: &str,
+) -> Result<(), ApiClientError> {
+    let target = package_dir.join(version);
+    let staging = package_dir.join(format!(".{}.staging", version));
+    let previous = package_dir.join(format!(".{}.previous", version));
+    if !target.exists() && previous.exists() {
+        // An earlier run stopped between moving the old version aside and replacing it
+        fs::rename(&previous, &target).map_err(|e| io_error(line!(), &previous, e))?;
+    }
+    for stale in [&staging, &previous] {
+        if stale.exists() {
+            fs::remove_dir_all(stale).map_err(|e| io_error(line!(), stale, e))?;
+        }
+    }
+
+    unpack(archive, &staging, url)?;
+    let replaced = target.exists();
+    if replaced {
+        fs::rename(&target, &previous).map_err(|e| io_error(line!(), &target, e))?;
+    }
+    if let Err(e) = fs::rename(&staging, &target) {
+        if replaced {
+            let _ = fs::rename(&previous, &target);
+        }
+        return Err(io_error(line!(), &target, e));
+    }
+    switch(package_dir, version)?;
+    if replaced {
+        if let Err(e) = fs::remove_dir_all(&previous) {
+            log::warn!("Unable to remove {}: {}", previous.display(), e);
+        }
+    }
+    Ok(())
+}
+
+/// Unpacks a downloaded archive into `destination`. Files that are not (gzipped) tarballs are
+/// copied in under the last segment of their download URL.
+fn unpack(archive: &Path, destination: &Path, url: &str) -> Result<(), ApiClientError> {
+    fs::create_dir_all(destination).map_err(|e| io_error(line!(), destination, e))?;
+
+    let mut header = [0u8; 262];
+    let read = File::open(archive)
+        .and_then(|mut file| file.read(&mut header))
+        .map_err(|e| io_error(line!(), archive, e))?;
+    let header = &header[..read];
+
+    let file = File::open(archive).map_err(|e| io_error(line!(), archive, e))?;
+    let unpacked = if header.starts_with(&[0x1f, 0x8b]) {
+        tar::Archive::new(GzDecoder::new(file)).unpack(destination)
+    } else if header.get(257..262) == Some(b"ustar") {
+        tar::Archive::new(file).unpack(destination)
+    } else {
+        let file_name = url
+            .rsplit('/')
+            .next()
+            .filter(|segment| !segment.is_empty() && safe_name(segment).is_ok())
+            .unwrap_or("package");
+        fs::copy(archive, destination.join(file_name)).map(|_| ())
+    };
+    unpacked.map_err(|e| ApiClientError::new(line!(), format!("Unpacking failed: {}", e).as_str()))
+}
+
+/// Atomically points the `current` entry of a package at `version`
+#[cfg(unix)]
+fn switch(package_dir: &Path, version: &str) -> Result<(), ApiClientError> {
+    let next = package_dir.join(format!(".{}.tmp", CURRENT));
+    let _ = fs::remove_file(&next);
+    std::os::unix::fs::symlink(version, &next).map_err(|e| io_error(line!(), &next, e))?;
+    fs::rename(&next, package_dir.join(CURRENT)).map_err(|e| io_error(line!(), &next, e))
+}
+
+/// Atomically points the `current` entry of a package at `version`. Without symlinks the entry
+/// is a file holding the name of the version directory.
+#[cfg(not(unix))]
+fn switch(package_dir: &Path, version: &str) -> Result<(), ApiClientError> {
+    let next = package_dir.join(format!(".{}.tmp", CURRENT));
+    fs::write(&next, version).map_err(|e| io_error(line!(), &next, e))?;
+    fs::rename(&next, package_dir.join(CURRENT)).map_err(|e| io_error(line!(), &next, e))
+}
+
+/// Rejects server supplied names that would escape the package directory
+fn safe_name(name: &str) -> Result<&str, ApiClientError> {
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name == CURRENT
+        || name.contains(['/', '\\'])
+        || name.starts_with('.')
+    {
+        return Err(ApiClientError::new(
+            line!(),
+            format!("Invalid package name or version '{}'", name).as_str(),
+        ));
+    }
+    Ok(name)
+}
+
+fn io_error(code: u32, path: &Path, e: io::Error) -> ApiClientError {
+    ApiClientError::new(code, format!("{}: {}", path.display(), e).as_str())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    const CONTENTS: &[u8] = b"#!/bin/sh\necho installed package contents\n";
+
+    /// A directory of its own for each test, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!(
+                "opamp-packages-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Serves `CONTENTS` once, honouring a `Range: bytes=<offset>-` request header
+    ///
+    /// Returns:
+    ///
+    /// The download URL and the offset that was asked for
+    async fn serve_once() -> (String, tokio::task::JoinHandle<Option<usize>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/agent.sh", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buffer = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let read = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+            let offset = String::from_utf8(request)
+                .unwrap()
+                .lines()
+                .find_map(|line| {
+                    let line = line.to_ascii_lowercase();
+                    let range = line.strip_prefix("range: bytes=")?;
+                    range.trim_end_matches('-').parse::<usize>().ok()
+                });
+
+            let head = match offset {
+                Some(offset) => format!(
+                    "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n",
+                    offset,
+                    CONTENTS.len() - 1,
+                    CONTENTS.len()
+                ),
+                None => "HTTP/1.1 200 OK\r\n".to_string(),
+            };
+            let body = &CONTENTS[offset.unwrap_or(0)..];
+            let response = format!(
+                "{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                head,
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            stream.write_all(body).await.unwrap();
+            offset
+        });
+        (url, server)
+    }
+
+    fn sha256(contents: &[u8]) -> Vec<u8> {
+        Sha256::digest(contents).to_vec()
+    }
+
+    #[test]
+    fn verify_checks_the_content_hash() {
+        let dir = TempDir::new("verify");
+        let path = dir.0.join("download.part");
+        fs::write(&path, CONTENTS).unwrap();
+
+        assert!(verify(&path, &sha256(CONTENTS), false).is_ok());
+        assert!(verify(&path, &sha256(b"other contents"), false).is_err());
+    }
+
+    #[test]
+    fn verify_requires_a_content_hash_unless_allowed() {
+        let dir = TempDir::new("unverified");
+        let path = dir.0.join("download.part");
+        fs::write(&path, CONTENTS).unwrap();
+
+        assert!(verify(&path, &[], false).is_err());
+        assert!(verify(&path, &[], true).is_ok());
+    }
+
+    #[tokio::test]
+    async fn download_resumes_a_partial_file() {
+        let dir = TempDir::new("resume");
+        let partial = dir.0.join("agent.part");
+        fs::write(&partial, &CONTENTS[..10]).unwrap();
+
+        let (url, server) = serve_once().await;
+        let file = DownloadableFile {
+            download_url: url,
+            content_hash: sha256(CONTENTS),
+            ..DownloadableFile::default()
+        };
+        let manager = PackageManager::new(dir.0.join("packages"));
+        manager.download(&file, &partial).await.unwrap();
+
+        assert_eq!(server.await.unwrap(), Some(10));
+        assert_eq!(fs::read(&partial).unwrap(), CONTENTS);
+        assert!(verify(&partial, &file.content_hash, false).is_ok());
+    }
+
+    #[tokio::test]
+    async fn install_activates_the_new_version() {
+        let dir = TempDir::new("install");
+        let (url, server) = serve_once().await;
+        let package = PackageAvailable {
+            version: "1.0.0".to_string(),
+            file: Some(DownloadableFile {
+                download_url: url,
+                content_hash: sha256(CONTENTS),
+                ..DownloadableFile::default()
+            }),
+            hash: vec![1],
+            ..PackageAvailable::default()
+        };
+        let mut manager = PackageManager::new(&dir.0);
+        manager.offer(&PackagesAvailable {
+            packages: HashMap::from([("agent".to_string(), package)]),
+            all_packages_hash: vec![1],
+        });
+
+        let statuses = manager.install_next().await.unwrap();
+        assert_eq!(server.await.unwrap(), None);
+        let status = &statuses.packages["agent"];
+        assert_eq!(status.status, PackageStatusEnum::Installed as i32);
+        assert_eq!(
+            fs::read(manager.current_dir("agent").join("agent.sh")).unwrap(),
+            CONTENTS
+        );
+    }
+
+    #[tokio::test]
+    async fn download_gives_up_on_a_stalled_server() {
+        let dir = TempDir::new("stalled");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/agent.sh", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let connection = listener.accept().await;
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            drop(connection);
+        });
+
+        let file = DownloadableFile {
+            download_url: url,
+            ..DownloadableFile::default()
+        };
+        let manager = PackageManager::new(&dir.0)
+            .with_timeouts(Duration::from_secs(1), Duration::from_millis(200));
+        let started = std::time::Instant::now();
+        assert!(manager
+            .download(&file, &dir.0.join("agent.part"))
+            .await
+            .is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn offer_removes_packages_no_longer_offered() {
+        let dir = TempDir::new("withdrawn");
+        let package = |hash: u8| PackageAvailable {
+            version: "1.0.0".to_string(),
+            hash: vec![hash],
+            ..PackageAvailable::default()
+        };
+        let mut manager = PackageManager::new(&dir.0);
+        manager.offer(&PackagesAvailable {
+            packages: HashMap::from([
+                ("agent".to_string(), package(1)),
+                ("plugin".to_string(), package(2)),
+            ]),
+            all_packages_hash: vec![1],
+        });
+        let plugin = dir.0.join("plugin").join("1.0.0");
+        fs::create_dir_all(&plugin).unwrap();
+
+        let statuses = manager
+            .offer(&PackagesAvailable {
+                packages: HashMap::from([("agent".to_string(), package(1))]),
+                all_packages_hash: vec![2],
+            })
+            .unwrap();
+        assert_eq!(statuses.packages.keys().collect::<Vec<_>>(), ["agent"]);
+
+        // The remaining package has no file, so only the removal succeeds
+        manager.install_next().await;
+        assert!(!dir.0.join("plugin").exists());
+    }
+}
@@ -14,12 +14,12 @@
 //! ## Not supported
 //! The following will *not* be supported by this library
 //! ​
-//! * Tertiary agent functionality (metrics collection, lifecycle management, etc)
+//! * Collection of the managed agent's telemetry. Only the client's own metrics and logs are exported
+//! * Process supervision beyond starting, stopping and restarting a single task with the _extras_ launcher
 //! * Communication mechanism/protocol strictly between the supervisor and agent processes (i.e. not involving OpAMP protocol integration)
-//! * Package management (may have a limited pilot implementation in _extras_)
 //! * Any scripts/configs supporting the deployment of the supervisor or agent
 //! * Deployment options for end clients
-//! * Persistence of state in external storage
+//! * State stores other than local files. Applications can plug their own in through `store::StateStore`
 //! * Authorization or access control of any kind at a protocol level.
 //!  
 //! # Integrating otel-opamp-rs
@@ -27,15 +27,15 @@
 //! The easiest way to get started is to include and enable the following features in Cargo.toml
 //!
 //! ```toml
-//! otel-opamp-rs = { version = "0.0.8", features = ["http", "websocket", "extras"] }
+//! otel-opamp-rs = { version = "0.0.10", features = ["http", "websocket", "extras"] }
 //! ```
 //!
 //! Interfacing code needs to implement the following trait and its callbacks
 //!
-//! ```
+//! ```ignore
 //! pub trait ApiCallbacks {
 //!     fn get_configuration(&mut self) -> Result<Option<AgentConfigMap>, ApiClientError>;
-//!     fn get_features(&mut self) -> (u64, u64);
+//!     fn get_features(&mut self) -> (Capabilities, AgentFlags);
 //!     fn on_loop(&mut self) -> Result<Option<AgentToServer>, ApiClientError>;
 //!     fn on_error(
 //!         &mut self,
@@ -61,12 +61,25 @@
 //!         &mut self,
 //!         inbound: &ServerToAgent,
 //!     ) -> Result<Option<AgentToServer>, ApiClientError>;
+//!     // Optional connection lifecycle notifications
+//!     fn on_connect(&mut self, _reason: &str) {}
+//!     fn on_disconnect(&mut self, _reason: &DisconnectReason) {}
+//!     fn on_reconnect(&mut self, _reason: &str) {}
+//!     fn on_connection_settings_rejected(&mut self, _error: &ApiClientError) {}
+//!     fn on_instance_uid_changed(&mut self, _previous: &InstanceUid, _current: &InstanceUid) {}
+//!     fn on_server_error(&mut self, _error: &ServerError) {}
+//!     fn get_available_components(&mut self) -> Option<AvailableComponents> {
+//!         None
+//!     }
+//!     fn get_description(&mut self, default: DescriptionBuilder) -> AgentDescription {
+//!         default.build()
+//!     }
 //! }
 //! ```
 //!
 //! To kick-start the API and poll it for data, you can go about it like so:
 //!
-//! ```
+//! ```ignore
 //! pub struct Supervisor {
 //! }
 //!
@@ -78,15 +91,15 @@
 //!     pub fn run() {
 //!         // Do your Supervisor/client initialization here
 //!
-//!         // Get an API handle
-//!         let mut handle = Api::new(
+//!         // Get an API handle. Invalid settings are reported rather than panicking
+//!         let mut handle = Api::try_new(
 //!            ConnectionSettings {
 //!                server_endpoint: server.endpoint,
 //!                api_key: server.api_key.clone(),
 //!                debugmode: args.options.debugmode,
 //!                ..Default::default()
 //!                }, Box::new(self),
-//!             );
+//!             )?;
 //!
 //!         // Execution loop
 //!         loop {
@@ -105,6 +118,30 @@
 //!
 //! The API also auto generates a poll message every 60 seconds to the server as required by OpAMP
 //!
+//! Health is reported by the application through `Api::set_health()` for the supervisor and
+//! `Api::set_component_health()` for each managed process. Being connected does not make the
+//! agent healthy.
+//!
+//! Status reports are compressed as described by the specification: a status section (description,
+//! health, effective config, remote config status and package statuses) is only sent when it differs
+//! from what was last delivered. The full state is sent after every (re)connect and whenever the
+//! server sets the `ReportFullState` flag.
+//!
+//! With `ConnectionSettings::state_dir` set, the instance id, the remote config status, the effective
+//! config, the package statuses and the message sequence number survive restarts, so the first report
+//! after a restart reflects what is applied and configs are not applied again. `Api::set_state_store()` plugs in other storage.
+//!
+//! With the `metrics` feature, a client declaring `REPORTS_OWN_METRICS` exports its connection state,
+//! message and error counts, back-off and the CPU and memory use of the process every 60 seconds over
+//! OTLP/HTTP to the destination the server offers.
+//!
+//! With the `logs` feature, an `extras::logs::OtlpLogger` installed as the global logger batches log
+//! records and `Api::set_logs_exporter()` ships them to the `own_logs` destination the server offers.
+//!
+//! With the `connections` feature, `Api::set_other_connections()` writes the `other_connections`
+//! offered for the managed agent to an env file or YAML fragment and restarts the agent, or invokes
+//! `ApiCallbacks::on_other_connections_changed()`, when it changes.
+//!
 //! # Under the hood
 //!
 //! This crate consists of a number of modules that provide a range of functionality
@@ -118,15 +155,20 @@
 //! gives it a high degree of predictability and robustness and simplifies debugging immensely.
 //!
 //! Running the code in debug mode shows state transition messages in detail to give you an
-//! insight of what its doing.
+//! insight of what its doing. Applications can inspect the connection at any time with
+//! `Api::status()` or follow every transition through the receiver returned by `Api::subscribe()`.
 //!
 //! ## Channel support
 //!
 //! The FSM requires supported network channels to implement the Channel trait
 //!
-//! ```
-//! pub trait Channel: Send {
+//! ```ignore
+//! pub trait Channel<'a>: Send {
 //!     fn get_instance_id(&self) -> &String;
+//!     /// The connection state shared by every transport, which the `Api` calls into for
+//!     /// everything but the state transitions
+//!     fn session(&self) -> &Session<'a>;
+//!     fn session_mut(&mut self) -> &mut Session<'a>;
 //!     // State transition handlers
 //!     async fn trigger(&mut self);
 //!     async fn connect(&mut self) -> Result<StateResponse, ApiClientError>;
@@ -176,10 +218,21 @@
 //!
 
 pub mod api;
+#[cfg(any(feature = "http", feature = "websocket"))]
+pub(crate) mod children;
+#[cfg(any(feature = "http", feature = "websocket"))]
+pub(crate) mod compression;
+pub mod description;
 pub mod extras;
 #[cfg(feature = "http")]
 pub mod httpclient;
 pub mod opamp;
+#[cfg(any(feature = "http", feature = "websocket"))]
+pub(crate) mod session;
 pub mod state;
+#[cfg(any(feature = "http", feature = "websocket"))]
+pub mod store;
+#[cfg(any(feature = "http", feature = "websocket"))]
+pub(crate) mod tls;
 #[cfg(feature = "websocket")]
 pub mod wsclient;
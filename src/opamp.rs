@@ -8,8 +8,14 @@ pub mod spec {
 
 #[async_trait]
 /// The `Channel` trait is what different transports would implement to support OpAMP
-pub trait Channel: Send {
+pub trait Channel<'a>: Send {
     fn get_instance_id(&self) -> &String;
+    /// The connection state shared by every transport, which the `Api` calls into for
+    /// everything but the state transitions
+    #[cfg(any(feature = "http", feature = "websocket"))]
+    fn session(&self) -> &crate::session::Session<'a>;
+    #[cfg(any(feature = "http", feature = "websocket"))]
+    fn session_mut(&mut self) -> &mut crate::session::Session<'a>;
     // State transition handlers
     async fn trigger(&mut self);
     async fn connect(&mut self) -> Result<StateResponse, ApiClientError>;
@@ -19,6 +25,94 @@ pub trait Channel: Send {
     async fn wait(&mut self) -> Result<StateResponse, ApiClientError>;
 }
 
+/// A 16 byte agent instance identifier as defined by the current OpAMP specification.
+///
+/// The textual form is a ULID. UUID strings are accepted when parsing so identities assigned by
+/// other tooling can be reused. With the `legacy-proto` feature the identifier is put on the wire
+/// as its ULID string, as expected by servers implementing the older string based revision.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct InstanceUid([u8; 16]);
+
+impl InstanceUid {
+    /// Generates a new time ordered identifier
+    pub fn generate() -> InstanceUid {
+        util::generate_ulid().into()
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+
+    /// Encodes the identifier for the `instance_uid` fields of OpAMP messages
+    pub fn to_wire(&self) -> Vec<u8> {
+        #[cfg(feature = "legacy-proto")]
+        {
+            self.to_string().into_bytes()
+        }
+        #[cfg(not(feature = "legacy-proto"))]
+        {
+            self.0.to_vec()
+        }
+    }
+
+    /// Decodes an `instance_uid` field. Both the 16 byte and the legacy string encodings are
+    /// accepted regardless of the wire format in use.
+    pub fn from_wire(bytes: &[u8]) -> Result<InstanceUid, ApiClientError> {
+        if let Ok(raw) = <[u8; 16]>::try_from(bytes) {
+            return Ok(InstanceUid(raw));
+        }
+        std::str::from_utf8(bytes)
+            .map_err(|_| ApiClientError::new(line!(), "Malformed instance_uid"))?
+            .parse()
+    }
+}
+
+impl From<[u8; 16]> for InstanceUid {
+    fn from(bytes: [u8; 16]) -> InstanceUid {
+        InstanceUid(bytes)
+    }
+}
+
+impl From<ulid::Ulid> for InstanceUid {
+    fn from(ulid: ulid::Ulid) -> InstanceUid {
+        InstanceUid(ulid.0.to_be_bytes())
+    }
+}
+
+impl From<InstanceUid> for ulid::Ulid {
+    fn from(uid: InstanceUid) -> ulid::Ulid {
+        ulid::Ulid(u128::from_be_bytes(uid.0))
+    }
+}
+
+impl std::str::FromStr for InstanceUid {
+    type Err = ApiClientError;
+
+    /// Parses a ULID, or a UUID in its hyphenated or plain hexadecimal form
+    fn from_str(text: &str) -> Result<InstanceUid, ApiClientError> {
+        if let Ok(ulid) = ulid::Ulid::from_string(text) {
+            return Ok(ulid.into());
+        }
+
+        let hex: String = text.chars().filter(|c| *c != '-').collect();
+        if hex.len() == 32 {
+            if let Ok(value) = u128::from_str_radix(&hex, 16) {
+                return Ok(InstanceUid(value.to_be_bytes()));
+            }
+        }
+        Err(ApiClientError::new(
+            line!(),
+            format!("Invalid instance id {}", text).as_str(),
+        ))
+    }
+}
+
+impl std::fmt::Display for InstanceUid {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", ulid::Ulid::from(*self))
+    }
+}
+
 #[macro_export]
 macro_rules! get_time_nanos {
     () => {
@@ -27,31 +121,118 @@ macro_rules! get_time_nanos {
 }
 
 pub mod util {
-    use rand::RngCore;
-    use ulid::Generator;
+    use super::spec::{AgentToServer, ServerToAgent};
+    use crate::api::ApiClientError;
+    use prost::Message;
 
     pub fn get_time_nanos() -> u128 {
         let start = std::time::SystemTime::now();
-        let since_the_epoch = start
+        // A clock set before the epoch reports zero rather than aborting the client
+        start
             .duration_since(std::time::UNIX_EPOCH)
-            .expect("Time error");
-        since_the_epoch.as_nanos()
+            .unwrap_or_default()
+            .as_nanos()
     }
 
     pub fn generate_ulid() -> ulid::Ulid {
-        let dt = std::time::SystemTime::now();
-        let mut entropy = [0; 10];
-        let mut rng = rand::thread_rng();
-        rng.fill_bytes(&mut entropy[6..]);
-        let mut gen = Generator::new();
-        gen.generate_from_datetime_with_source(dt, &mut rng)
-            .unwrap()
+        ulid::Ulid::from_datetime(std::time::SystemTime::now())
+    }
+
+    /// Decodes a protobuf encoded `ServerToAgent`, reporting malformed payloads as errors
+    pub fn decode_server_message(bytes: &[u8]) -> Result<ServerToAgent, ApiClientError> {
+        ServerToAgent::decode(bytes).map_err(|e| {
+            ApiClientError::new(
+                line!(),
+                format!("Malformed ServerToAgent message: {}", e).as_str(),
+            )
+        })
+    }
+
+    /// Encodes an outbound websocket frame, prefixed with the zero varint header OpAMP requires
+    pub fn encode_ws_message(msg: &AgentToServer) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(msg.encoded_len() + 1);
+        prost::encoding::encode_varint(0, &mut buf);
+        buf.extend(msg.encode_to_vec());
+        buf
+    }
+
+    /// Decodes a websocket frame. OpAMP prefixes websocket payloads with a varint header
+    /// which is skipped before decoding the protobuf body.
+    pub fn decode_ws_message(bytes: &[u8]) -> Result<ServerToAgent, ApiClientError> {
+        let mut body = bytes;
+        prost::encoding::decode_varint(&mut body).map_err(|e| {
+            ApiClientError::new(
+                line!(),
+                format!("Malformed websocket header: {}", e).as_str(),
+            )
+        })?;
+        decode_server_message(body)
+    }
+}
+
+pub mod capabilities {
+    use super::spec::{AgentCapabilities, AgentToServerFlags};
+    use bitflags::bitflags;
+
+    bitflags! {
+        /// Typed form of the `AgentCapabilities` advertised to the server
+        #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+        pub struct Capabilities: u64 {
+            const REPORTS_STATUS = AgentCapabilities::ReportsStatus as u64;
+            const ACCEPTS_REMOTE_CONFIG = AgentCapabilities::AcceptsRemoteConfig as u64;
+            const REPORTS_EFFECTIVE_CONFIG = AgentCapabilities::ReportsEffectiveConfig as u64;
+            const ACCEPTS_PACKAGES = AgentCapabilities::AcceptsPackages as u64;
+            const REPORTS_PACKAGE_STATUSES = AgentCapabilities::ReportsPackageStatuses as u64;
+            const REPORTS_OWN_TRACES = AgentCapabilities::ReportsOwnTraces as u64;
+            const REPORTS_OWN_METRICS = AgentCapabilities::ReportsOwnMetrics as u64;
+            const REPORTS_OWN_LOGS = AgentCapabilities::ReportsOwnLogs as u64;
+            const ACCEPTS_OPAMP_CONNECTION_SETTINGS =
+                AgentCapabilities::AcceptsOpAmpConnectionSettings as u64;
+            const ACCEPTS_OTHER_CONNECTION_SETTINGS =
+                AgentCapabilities::AcceptsOtherConnectionSettings as u64;
+            const ACCEPTS_RESTART_COMMAND = AgentCapabilities::AcceptsRestartCommand as u64;
+            const REPORTS_HEALTH = AgentCapabilities::ReportsHealth as u64;
+            const REPORTS_REMOTE_CONFIG = AgentCapabilities::ReportsRemoteConfig as u64;
+            const REPORTS_HEARTBEAT = AgentCapabilities::ReportsHeartbeat as u64;
+            const REPORTS_AVAILABLE_COMPONENTS =
+                AgentCapabilities::ReportsAvailableComponents as u64;
+        }
+    }
+
+    bitflags! {
+        /// Typed form of the `AgentToServerFlags` sent with every message
+        #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+        pub struct AgentFlags: u64 {
+            const REQUEST_INSTANCE_UID = AgentToServerFlags::RequestInstanceUid as u64;
+        }
+    }
+
+    impl Capabilities {
+        /// Reports combinations of capabilities that the specification or this library consider
+        /// inconsistent. Each entry describes one problem.
+        pub fn inconsistencies(&self) -> Vec<&'static str> {
+            let mut problems = vec![];
+            if !self.contains(Capabilities::REPORTS_STATUS) {
+                problems.push("ReportsStatus must be set by all agents");
+            }
+            if self.contains(Capabilities::ACCEPTS_REMOTE_CONFIG)
+                && !self.contains(Capabilities::REPORTS_REMOTE_CONFIG)
+            {
+                problems.push("AcceptsRemoteConfig without ReportsRemoteConfig hides remote config status from the server");
+            }
+            if self.contains(Capabilities::ACCEPTS_PACKAGES)
+                && !self.contains(Capabilities::REPORTS_PACKAGE_STATUSES)
+            {
+                problems.push("AcceptsPackages without ReportsPackageStatuses hides package status from the server");
+            }
+            problems
+        }
     }
 }
 
 pub mod defaults {
     use super::spec::*;
-    use sysinfo::{System, SystemExt};
+    use crate::description::DescriptionBuilder;
 
     pub fn effective_config() -> EffectiveConfig {
         EffectiveConfig { config_map: None }
@@ -73,64 +254,86 @@ pub mod defaults {
         }
     }
 
-    pub fn agent_health() -> AgentHealth {
-        AgentHealth {
+    pub fn agent_health() -> ComponentHealth {
+        ComponentHealth {
             healthy: false,
             start_time_unix_nano: get_time_nanos!() as u64,
             last_error: "".to_string(),
+            status: "".to_string(),
+            status_time_unix_nano: get_time_nanos!() as u64,
+            component_health_map: std::collections::HashMap::new(),
         }
     }
 
+    /// Describes the agent with the identifying `service.*` attributes and whatever the built-in
+    /// detectors find. `instance_id` has to match the instance_uid the agent connects with.
+    pub fn agent_description_for(name: &str, version: &str, instance_id: &str) -> AgentDescription {
+        DescriptionBuilder::new(name, version, instance_id)
+            .with_default_detectors()
+            .build()
+    }
+
+    /// Describes the agent like `agent_description_for`, with a newly generated
+    /// `service.instance.id` that does not match the instance_uid the agent connects with.
+    #[deprecated(note = "use `agent_description_for` with the instance id the agent connects with")]
     pub fn agent_description(name: &str, version: &str) -> AgentDescription {
-        let mut identifying_attributes = Vec::new();
-        let mut non_identifying_attributes = Vec::new();
-        // Identifying attributes
-        identifying_attributes.push(KeyValue {
-            key: "service.name".to_string(),
-            value: Some(AnyValue {
-                value: Some(any_value::Value::StringValue(name.to_string())),
-            }),
-        });
-        identifying_attributes.push(KeyValue {
-            key: "service.version".to_string(),
-            value: Some(AnyValue {
-                value: Some(any_value::Value::StringValue(version.to_string())),
-            }),
-        });
-        identifying_attributes.push(KeyValue {
-            key: "service.instance.id".to_string(),
-            value: Some(AnyValue {
-                value: Some(any_value::Value::StringValue(
-                    super::util::generate_ulid().to_string(),
-                )),
-            }),
-        });
-        // Non-identifying attributes
-        let sys = System::new_all();
-        non_identifying_attributes.push(KeyValue {
-            key: "os.type".to_string(),
-            value: Some(AnyValue {
-                value: Some(any_value::Value::StringValue(
-                    std::env::consts::OS.to_string(),
-                )),
-            }),
-        });
-        non_identifying_attributes.push(KeyValue {
-            key: "os.version".to_string(),
-            value: Some(AnyValue {
-                value: Some(any_value::Value::StringValue(sys.kernel_version().unwrap())),
-            }),
-        });
-        non_identifying_attributes.push(KeyValue {
-            key: "host.name".to_string(),
-            value: Some(AnyValue {
-                value: Some(any_value::Value::StringValue(sys.host_name().unwrap())),
-            }),
-        });
-
-        AgentDescription {
-            identifying_attributes,
-            non_identifying_attributes,
+        agent_description_for(name, version, &super::util::generate_ulid().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BYTES: [u8; 16] = [
+        0x01, 0x88, 0xe8, 0xf4, 0x5c, 0x4e, 0x7a, 0x3b, 0x9d, 0x2f, 0x1a, 0x2b, 0x3c, 0x4d, 0x5e,
+        0x6f,
+    ];
+
+    #[test]
+    fn instance_uid_from_ulid() {
+        let uid = InstanceUid::from(BYTES);
+        let text = uid.to_string();
+        assert_eq!(text.len(), 26);
+        assert_eq!(text.parse::<InstanceUid>().unwrap(), uid);
+    }
+
+    #[test]
+    fn instance_uid_from_uuid() {
+        let hyphenated: InstanceUid = "0188e8f4-5c4e-7a3b-9d2f-1a2b3c4d5e6f".parse().unwrap();
+        assert_eq!(hyphenated.as_bytes(), &BYTES);
+        let plain: InstanceUid = "0188E8F45C4E7A3B9D2F1A2B3C4D5E6F".parse().unwrap();
+        assert_eq!(plain.as_bytes(), &BYTES);
+    }
+
+    #[test]
+    fn instance_uid_rejects_malformed_text() {
+        for text in [
+            "",
+            "not an id",
+            "0188e8f4-5c4e-7a3b-9d2f-1a2b3c4d5e6",
+            "0188e8f4-5c4e-7a3b-9d2f-1a2b3c4d5e6g",
+        ] {
+            assert!(text.parse::<InstanceUid>().is_err(), "{}", text);
         }
     }
+
+    #[test]
+    fn instance_uid_from_wire() {
+        let uid = InstanceUid::from(BYTES);
+        assert_eq!(InstanceUid::from_wire(&BYTES).unwrap(), uid);
+        // Legacy servers send the ULID string, other tooling may send a UUID string
+        assert_eq!(
+            InstanceUid::from_wire(uid.to_string().as_bytes()).unwrap(),
+            uid
+        );
+        assert_eq!(
+            InstanceUid::from_wire(b"0188e8f4-5c4e-7a3b-9d2f-1a2b3c4d5e6f").unwrap(),
+            uid
+        );
+        assert_eq!(InstanceUid::from_wire(&uid.to_wire()).unwrap(), uid);
+
+        assert!(InstanceUid::from_wire(&[]).is_err());
+        assert!(InstanceUid::from_wire(&[0xff; 20]).is_err());
+    }
 }
@@ -0,0 +1,2718 @@
+use crate::api::{
+    ApiCallbacks, ApiClientError, ChildAgent, ConnectionSettings, CustomMessageHandler, ServerError,
+};
+use crate::children::Child;
+use crate::compression::{components_hash, StatusCompression};
+#[cfg(feature = "connections")]
+use crate::extras::connections::OtherConnections;
+use crate::description::{attribute, DescriptionBuilder};
+#[cfg(feature = "launcher")]
+use crate::extras::launcher::Task;
+#[cfg(feature = "logs")]
+use crate::extras::logs::LogsExporter;
+#[cfg(feature = "metrics")]
+use crate::extras::metrics::MetricsExporter;
+#[cfg(feature = "packages")]
+use crate::extras::packages::PackageManager;
+use crate::opamp::{capabilities::Capabilities, defaults, spec::*, InstanceUid};
+use crate::state::{ConnectionStatus, DisconnectReason, State, StateTransition};
+use crate::store::{FileStateStore, Identity, StateStore};
+use crate::tls;
+use prost::Message;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::broadcast;
+
+/// Number of transitions a slow subscriber may fall behind before it starts losing events
+const TRANSITION_BACKLOG: usize = 64;
+
+/// Heartbeat period used until the server offers one
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Time a connection with offered settings has to complete an exchange before the previous
+/// settings are restored
+const SETTINGS_CONFIRM_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The `Destination` struct describes where and how a transport connects to the server.
+///
+/// Properties:
+///
+/// * `address`: URL of the OpAMP endpoint.
+/// * `headers`: Additional headers sent when connecting or posting to the server.
+/// * `certificate`: Client certificate presented to the server.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Destination {
+    pub(crate) address: url::Url,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) certificate: Option<TlsCertificate>,
+}
+
+/// Key store name of the client certificate used for OpAMP connections
+const OPAMP_CERTIFICATE: &str = "opamp";
+
+/// Upper bound of the exponential back-off when the server is unavailable and did not say for how
+/// long
+const MAX_UNAVAILABLE_DELAY: Duration = Duration::from_secs(300);
+
+/// Time a restarted agent has to stay up before it is reported healthy
+#[cfg(feature = "launcher")]
+const RESTART_SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// State store keys of the status sections kept across restarts
+const REMOTE_CONFIG_STATUS: &str = "remote_config_status";
+const EFFECTIVE_CONFIG: &str = "effective_config";
+const PACKAGE_STATUSES: &str = "package_statuses";
+
+/// State store key of the last sequence number sent, so the server sees no gap after a restart
+const SEQUENCE_NUM: &str = "sequence_num";
+
+/// State store key of the hash of the other connection settings last written
+#[cfg(feature = "connections")]
+const OTHER_CONNECTIONS: &str = "other_connections";
+
+/// The `Session` struct holds the transport independent half of an OpAMP client. Both the HTTP
+/// and Websocket channels own one and delegate state keeping and message dispatch to it.
+///
+/// Properties:
+///
+/// * `settings`: The `ConnectionSettings` the client was created with.
+/// * `instance_uid`: The 16 byte form of `settings.instance_id`.
+/// * `identity`: Keeps the instance id in `settings.state_dir`, when one is configured.
+/// * `state_store`: Keeps the reported status sections across restarts, when configured.
+/// * `destination`: The server endpoint and headers the transport connects with.
+/// * `heartbeat_interval`: Period of idleness after which a heartbeat is sent to the server.
+/// * `callback`: The application callbacks, shared behind a mutex.
+/// * `agent_state`: `agent_state` is a `RefCell` that holds an optional `AgentToServer` struct. This
+///   struct represents the internal synchronized state of the agent.
+/// * `outbox`: Messages waiting to be sent to the server.
+/// * `seqno`: Sequence number of the last message sent to the server.
+/// * `backoff`: Number of consecutive failed connection attempts.
+/// * `retries_exhausted`: Set when the last back-off cycle ran out of retries.
+/// * `connected_once`: Whether a connection to the server was ever established.
+/// * `state`: Current state of the FSM.
+/// * `last_error`: The most recent error reported by a state transition.
+/// * `last_exchange`: Time of the last successful exchange with the server.
+/// * `messages_sent`: Number of messages delivered to the server.
+/// * `messages_received`: Number of messages received from the server.
+/// * `errors`: Number of connection, transport and server errors.
+/// * `transitions`: Broadcasts every state transition to subscribers.
+/// * `compression`: Tracks the status sections the server already has.
+/// * `fallback`: The last working destination and heartbeat interval, and the deadline for
+///   confirming the offered ones.
+/// * `reconnect`: Set when the transport should reconnect to pick up a new destination.
+/// * `certificate_key`: Private key of an outstanding certificate signing request.
+/// * `children`: Child agents reported over this connection, by instance_uid.
+/// * `available_components`: Full inventory of the components available in the managed agent.
+/// * `custom_handlers`: Handlers of the declared custom capabilities, by capability.
+/// * `rejected`: The HTTP request the server answered with an error, held again or dropped.
+/// * `hold_until`: Sending is paused until then after the server reported it is unavailable.
+/// * `unavailable`: Number of consecutive unavailable responses without a retry delay.
+/// * `remote_config`: A remote config reported as applying, handed to the application once that
+///   report went out.
+/// * `packages`: Installs package offers when the application set a package manager.
+/// * `restart_task`: Task restarted on a restart command, when the application opted in.
+/// * `restart_pending`: Set while a restart command waits for the unhealthy report to go out.
+/// * `restart`: The restart in progress, which runs off the poll path and yields its outcome.
+/// * `metrics`: Exports our own metrics to the destination offered by the server.
+/// * `logs`: Exports our own logs to the offered destination, when the application set it up.
+/// * `other_connections`: Writes the other connection offers for the agent, when set.
+pub struct Session<'a> {
+    pub(crate) settings: ConnectionSettings,
+    pub(crate) instance_uid: InstanceUid,
+    identity: Option<Identity>,
+    state_store: Option<Box<dyn StateStore>>,
+    pub(crate) destination: Destination,
+    pub(crate) heartbeat_interval: Duration,
+    pub(crate) callback: Arc<Mutex<Box<dyn ApiCallbacks + Send + Sync + 'a>>>,
+    pub(crate) agent_state: RefCell<Option<AgentToServer>>,
+    pub(crate) outbox: Vec<AgentToServer>,
+    pub(crate) seqno: u64,
+    pub(crate) backoff: u32,
+    retries_exhausted: bool,
+    connected_once: bool,
+    state: State,
+    last_error: Option<String>,
+    last_exchange: Option<SystemTime>,
+    messages_sent: u64,
+    messages_received: u64,
+    errors: u64,
+    transitions: broadcast::Sender<StateTransition>,
+    compression: StatusCompression,
+    fallback: Option<(Destination, Duration, Instant)>,
+    reconnect: bool,
+    certificate_key: Option<Vec<u8>>,
+    children: HashMap<InstanceUid, Child>,
+    available_components: Option<AvailableComponents>,
+    custom_handlers: HashMap<String, Box<dyn CustomMessageHandler + Send + Sync>>,
+    rejected: Option<AgentToServer>,
+    hold_until: Option<Instant>,
+    unavailable: u32,
+    remote_config: Option<ServerToAgent>,
+    #[cfg(feature = "packages")]
+    packages: Option<PackageManager>,
+    #[cfg(feature = "launcher")]
+    restart_task: Option<Arc<Task>>,
+    #[cfg(feature = "launcher")]
+    restart_pending: bool,
+    #[cfg(feature = "launcher")]
+    restart: Option<tokio::task::JoinHandle<Result<(), String>>>,
+    #[cfg(feature = "metrics")]
+    metrics: MetricsExporter,
+    #[cfg(feature = "logs")]
+    logs: Option<LogsExporter>,
+    #[cfg(feature = "connections")]
+    other_connections: Option<OtherConnections>,
+}
+
+impl<'a> Session<'a> {
+    pub(crate) fn new(
+        settings: ConnectionSettings,
+        cb: Box<dyn ApiCallbacks + Send + Sync + 'a>,
+    ) -> Result<Session<'a>, ApiClientError> {
+        let mut settings = settings;
+        let mut instance_uid = settings.instance_id.parse()?;
+        let identity = match settings.state_dir.as_ref() {
+            Some(state_dir) => {
+                let (identity, saved) = Identity::open(state_dir, &instance_uid)?;
+                instance_uid = saved;
+                settings.instance_id = saved.to_string();
+                Some(identity)
+            }
+            None => None,
+        };
+        let state_store = settings
+            .state_dir
+            .as_ref()
+            .map(|state_dir| Box::new(FileStateStore::new(state_dir)) as Box<dyn StateStore>);
+        let path = settings.server_endpoint.clone() + settings.listen_path.as_str();
+        let address = url::Url::parse(&path).map_err(|e| {
+            ApiClientError::new(
+                line!(),
+                format!("Invalid endpoint {}: {}", path, e).as_str(),
+            )
+        })?;
+        let certificate = settings
+            .key_store
+            .as_ref()
+            .and_then(|key_store| tls::load(key_store, OPAMP_CERTIFICATE));
+        let (transitions, _) = broadcast::channel(TRANSITION_BACKLOG);
+
+        let mut session = Session {
+            settings,
+            instance_uid,
+            identity,
+            state_store,
+            destination: Destination {
+                address,
+                headers: vec![],
+                certificate,
+            },
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            callback: Arc::new(Mutex::new(cb)),
+            agent_state: RefCell::new(None),
+            outbox: vec![],
+            seqno: 0,
+            backoff: 0,
+            retries_exhausted: false,
+            connected_once: false,
+            state: State::Disconnected("".to_string()),
+            last_error: None,
+            last_exchange: None,
+            messages_sent: 0,
+            messages_received: 0,
+            errors: 0,
+            transitions,
+            compression: StatusCompression::default(),
+            fallback: None,
+            reconnect: false,
+            certificate_key: None,
+            children: HashMap::new(),
+            available_components: None,
+            custom_handlers: HashMap::new(),
+            rejected: None,
+            hold_until: None,
+            unavailable: 0,
+            remote_config: None,
+            #[cfg(feature = "packages")]
+            packages: None,
+            #[cfg(feature = "launcher")]
+            restart_task: None,
+            #[cfg(feature = "launcher")]
+            restart_pending: false,
+            #[cfg(feature = "launcher")]
+            restart: None,
+            #[cfg(feature = "metrics")]
+            metrics: MetricsExporter::new(),
+            #[cfg(feature = "logs")]
+            logs: None,
+            #[cfg(feature = "connections")]
+            other_connections: None,
+        };
+        session.restore_sequence_num();
+        Ok(session)
+    }
+
+    /// Returns the current FSM state
+    pub(crate) fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// Moves the FSM to `next`, recording errors and notifying subscribers
+    pub(crate) fn transition(&mut self, next: State) {
+        #[cfg(feature = "metrics")]
+        self.metrics.record(self.status());
+        if std::mem::discriminant(&self.state) == std::mem::discriminant(&next) {
+            self.state = next;
+            return;
+        }
+
+        log::debug!("State transition {:?} -> {:?}", &self.state, &next);
+        match &next {
+            State::Connected(_) => {
+                // A new connection starts with a full state report
+                self.backoff = 0;
+                self.compression.reset();
+            }
+            State::Disconnected(reason) => {
+                if !reason.is_empty() {
+                    self.last_error = Some(reason.clone());
+                    self.errors += 1;
+                }
+                self.restore_expired_destination();
+            }
+            State::Connecting(_) => self.restore_expired_destination(),
+            _ => {}
+        }
+        self.notify_lifecycle(&next);
+
+        let previous = std::mem::replace(&mut self.state, next);
+        // Sending only fails when nobody is subscribed
+        let _ = self.transitions.send(StateTransition {
+            from: previous,
+            to: self.state.clone(),
+            timestamp: SystemTime::now(),
+        });
+    }
+
+    /// Drives the connection lifecycle callbacks from a state change
+    fn notify_lifecycle(&mut self, next: &State) {
+        let mut func = self.callback.lock().unwrap();
+        match (&self.state, next) {
+            (State::Connecting(_), State::Connected(reason)) => {
+                if self.connected_once {
+                    log::info!("Reconnected to server");
+                    func.on_reconnect(reason);
+                } else {
+                    log::info!("Connected to server");
+                    func.on_connect(reason);
+                }
+                self.connected_once = true;
+            }
+            (current, State::Connecting(reason) | State::Disconnected(reason))
+                if current.is_connected() =>
+            {
+                let reason = DisconnectReason::ConnectionLost(reason.clone());
+                log::warn!("Disconnected from server: {}", reason);
+                func.on_disconnect(&reason);
+            }
+            (_, State::Disconnected(reason)) if self.retries_exhausted => {
+                self.retries_exhausted = false;
+                let reason = DisconnectReason::RetriesExhausted(reason.clone());
+                log::warn!("Disconnected from server: {}", reason);
+                func.on_disconnect(&reason);
+            }
+            _ => {}
+        }
+    }
+
+    /// Reports a snapshot of the connection status
+    pub(crate) fn status(&self) -> ConnectionStatus {
+        ConnectionStatus {
+            state: self.state.clone(),
+            last_error: self.last_error.clone(),
+            last_exchange: self.last_exchange,
+            sequence_num: self.seqno,
+            retries: self.backoff,
+            messages_sent: self.messages_sent,
+            messages_received: self.messages_received,
+            errors: self.errors,
+        }
+    }
+
+    /// Returns a receiver for all subsequent state transitions
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<StateTransition> {
+        self.transitions.subscribe()
+    }
+
+    /// Records a successful exchange with the server. The first exchange after switching to
+    /// offered connection settings confirms them.
+    pub(crate) fn record_exchange(&mut self) {
+        self.last_exchange = Some(SystemTime::now());
+        if self.fallback.take().is_some() {
+            log::info!(
+                "Offered connection settings confirmed for {}",
+                self.destination.address
+            );
+            if let Some(certificate) = self.destination.certificate.clone() {
+                if self.certificate_key.as_ref() == Some(&certificate.private_key) {
+                    self.certificate_key = None;
+                }
+                self.store_certificate(OPAMP_CERTIFICATE, &certificate);
+            }
+        }
+    }
+
+    /// Saves a certificate in the key store, if one is configured
+    fn store_certificate(&mut self, name: &str, certificate: &TlsCertificate) {
+        if let Some(key_store) = &self.settings.key_store {
+            if let Err(e) = tls::persist(key_store, name, certificate) {
+                self.reject_settings(e);
+            }
+        }
+    }
+
+    /// Records and reports offered connection settings that could not be used
+    fn reject_settings(&mut self, error: ApiClientError) {
+        log::warn!("Rejecting offered connection settings: {}", error);
+        self.record_error(&error);
+        let mut func = self.callback.lock().unwrap();
+        func.on_connection_settings_rejected(&error);
+    }
+
+    /// Completes an offered certificate and checks that it can be used. An offer without a
+    /// private key is paired with the key of an outstanding certificate signing request, or
+    /// else with the key of the certificate currently in use.
+    fn accept_certificate(
+        &self,
+        offered: &TlsCertificate,
+    ) -> Result<TlsCertificate, ApiClientError> {
+        let mut certificate = offered.clone();
+        if certificate.private_key.is_empty() {
+            certificate.private_key = self
+                .certificate_key
+                .clone()
+                .or_else(|| {
+                    self.destination
+                        .certificate
+                        .as_ref()
+                        .map(|current| current.private_key.clone())
+                })
+                .ok_or_else(|| {
+                    ApiClientError::new(line!(), "Offered certificate has no private key")
+                })?;
+        }
+        tls::validate(&certificate)?;
+        Ok(certificate)
+    }
+
+    /// Generates a new key pair and queues a certificate signing request for it. The subject
+    /// carries our instance_uid. The certificate the server signs arrives as an OpAMP connection
+    /// settings offer and is installed with the generated key.
+    #[cfg(feature = "csr")]
+    pub(crate) fn request_certificate(&mut self) -> Result<(), ApiClientError> {
+        // Capabilities are known once the initial state was gathered
+        self.get_status()?;
+        if !self.has_capability(Capabilities::ACCEPTS_OPAMP_CONNECTION_SETTINGS) {
+            return Err(ApiClientError::new(
+                line!(),
+                "Certificate requests need the AcceptsOpAMPConnectionSettings capability",
+            ));
+        }
+
+        let (csr, private_key) = tls::certificate_request(&self.instance_uid.to_string())?;
+        self.certificate_key = Some(private_key);
+        log::info!("Requesting a client certificate for {}", self.instance_uid);
+        self.outbox.push(AgentToServer {
+            instance_uid: self.instance_uid.to_wire(),
+            connection_settings_request: Some(ConnectionSettingsRequest {
+                opamp: Some(OpAmpConnectionSettingsRequest {
+                    certificate_request: Some(CertificateRequest { csr }),
+                }),
+            }),
+            ..AgentToServer::default()
+        });
+        Ok(())
+    }
+
+    /// Returns whether the transport has to reconnect to pick up new connection settings
+    pub(crate) fn take_reconnect(&mut self) -> bool {
+        std::mem::take(&mut self.reconnect)
+    }
+
+    /// Validates an `OpAMPConnectionSettings` offer and switches to it. The transport reconnects
+    /// and falls back to the current destination and heartbeat interval if no exchange succeeds
+    /// in time. An offer that only changes the heartbeat interval takes effect right away.
+    fn apply_opamp_settings(
+        &mut self,
+        offer: &OpAmpConnectionSettings,
+    ) -> Result<(), ApiClientError> {
+        let mut next = self.destination.clone();
+        if !offer.destination_endpoint.is_empty() {
+            let address = url::Url::parse(&offer.destination_endpoint).map_err(|e| {
+                ApiClientError::new(
+                    line!(),
+                    format!("Invalid endpoint {}: {}", offer.destination_endpoint, e).as_str(),
+                )
+            })?;
+            // The transport was chosen by the original scheme and can not change
+            let is_http = |url: &url::Url| url.scheme().starts_with("http");
+            if is_http(&address) != is_http(&self.destination.address) {
+                return Err(ApiClientError::new(
+                    line!(),
+                    format!("Endpoint {} needs a different transport", address).as_str(),
+                ));
+            }
+            next.address = address;
+        }
+        if let Some(headers) = &offer.headers {
+            next.headers = headers
+                .headers
+                .iter()
+                .map(validate_header)
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(certificate) = &offer.certificate {
+            next.certificate = Some(self.accept_certificate(certificate)?);
+        }
+        // Zero leaves the current heartbeat period in place
+        let heartbeat_interval = if offer.heartbeat_interval_seconds > 0
+            && self.has_capability(Capabilities::REPORTS_HEARTBEAT)
+        {
+            Duration::from_secs(offer.heartbeat_interval_seconds)
+        } else {
+            self.heartbeat_interval
+        };
+        let previous_heartbeat =
+            std::mem::replace(&mut self.heartbeat_interval, heartbeat_interval);
+        if heartbeat_interval != previous_heartbeat {
+            log::debug!("Heartbeat interval set to {:?}", heartbeat_interval);
+        }
+        if next == self.destination {
+            return Ok(());
+        }
+
+        log::info!(
+            "Switching to offered connection settings for {}",
+            next.address
+        );
+        let previous = std::mem::replace(&mut self.destination, next);
+        // An earlier switch that is still unconfirmed does not count as working
+        let (previous, previous_heartbeat) = self
+            .fallback
+            .take()
+            .map_or((previous, previous_heartbeat), |(working, heartbeat, _)| {
+                (working, heartbeat)
+            });
+        self.fallback = Some((
+            previous,
+            previous_heartbeat,
+            Instant::now() + SETTINGS_CONFIRM_TIMEOUT,
+        ));
+        self.reconnect = true;
+        Ok(())
+    }
+
+    /// Goes back to the last working destination once offered settings failed to connect in time
+    fn restore_expired_destination(&mut self) {
+        if self
+            .fallback
+            .as_ref()
+            .is_some_and(|(_, _, deadline)| Instant::now() >= *deadline)
+        {
+            if let Some((previous, heartbeat_interval, _)) = self.fallback.take() {
+                let error = ApiClientError::new(
+                    line!(),
+                    format!(
+                        "Offered connection settings for {} did not connect in time. Restoring {}",
+                        self.destination.address, previous.address
+                    )
+                    .as_str(),
+                );
+                self.destination = previous;
+                self.heartbeat_interval = heartbeat_interval;
+                self.backoff = 0;
+                self.reject_settings(error);
+            }
+        }
+    }
+
+    /// Records an error that did not result in a state transition
+    pub(crate) fn record_error(&mut self, error: &ApiClientError) {
+        self.last_error = Some(error.to_string());
+        self.errors += 1;
+    }
+
+    /// Tracks a failed connection attempt. Fails once `OPAMP_CONNECT_RETRIES` (default 10)
+    /// consecutive attempts have been made, after which the next attempt starts a new cycle.
+    ///
+    /// Returns:
+    ///
+    /// The exponentially increasing period the transport waits before its next attempt
+    pub(crate) fn backoff(&mut self, reason: &str) -> Result<Duration, ApiClientError> {
+        self.backoff += 1;
+
+        let connect_retries = std::env::var("OPAMP_CONNECT_RETRIES")
+            .ok()
+            .and_then(|retries| retries.parse::<u32>().ok())
+            .unwrap_or(10);
+        if self.backoff > connect_retries {
+            log::error!("Failed to connect after {} retries", connect_retries);
+            // Start a fresh back-off cycle on the next attempt
+            self.backoff = 0;
+            self.retries_exhausted = true;
+            return Err(ApiClientError {
+                code: line!(),
+                details: format!("Failed to connect to endpoint: {}", reason),
+            });
+        }
+        Ok(Duration::from_secs(2_u64.saturating_pow(self.backoff)))
+    }
+
+    /// Stamps an outbound message with the next sequence number and our advertised features.
+    /// Status sections of our own messages are folded into the cached agent state and left out
+    /// when the server already has them.
+    pub(crate) fn prepare(&mut self, message: &mut AgentToServer) {
+        self.seqno += 1;
+        message.sequence_num = self.seqno;
+        if message.instance_uid.is_empty() {
+            message.instance_uid = self.instance_uid.to_wire();
+        }
+        let ours = self.is_own_message(message);
+        let child = if ours {
+            None
+        } else {
+            InstanceUid::from_wire(&message.instance_uid)
+                .ok()
+                .and_then(|uid| self.children.get(&uid))
+        };
+        if let Some(child) = child {
+            // Children advertise their own capabilities
+            message.capabilities = child.capabilities.bits();
+            message.flags = 0;
+        } else if let Some(state) = self.agent_state.borrow_mut().as_mut() {
+            message.capabilities = state.capabilities;
+            message.flags = state.flags;
+            if ours {
+                StatusCompression::merge(state, message);
+            }
+        } else {
+            log::warn!("Missing persistent agent state");
+        }
+        self.persist(message);
+        if ours {
+            self.compression.compress(message);
+        }
+    }
+
+    /// Keeps the reported state in `store` from now on
+    pub(crate) fn set_state_store(&mut self, store: Box<dyn StateStore>) {
+        self.state_store = Some(store);
+        self.restore_sequence_num();
+    }
+
+    /// Continues after the last sequence number saved in the state store
+    fn restore_sequence_num(&mut self) {
+        if let Some(seqno) = self.restore::<u64>(SEQUENCE_NUM) {
+            self.seqno = self.seqno.max(seqno);
+        }
+    }
+
+    /// Reads a status section saved by a previous run
+    fn restore<T: Message + Default>(&self, key: &str) -> Option<T> {
+        let saved = match self.state_store.as_ref()?.load(key) {
+            Ok(saved) => saved?,
+            Err(e) => {
+                log::warn!("Unable to restore {}: {}", key, e);
+                return None;
+            }
+        };
+        match T::decode(saved.as_slice()) {
+            Ok(section) => Some(section),
+            Err(e) => {
+                log::warn!("Discarding saved {}: {}", key, e);
+                None
+            }
+        }
+    }
+
+    /// Saves the sequence number of an outbound message and, for our own messages, its status
+    /// sections
+    fn persist(&mut self, message: &AgentToServer) {
+        let ours = self.is_own_message(message);
+        let store = match self.state_store.as_mut() {
+            Some(store) => store,
+            None => return,
+        };
+        let mut sections = vec![(SEQUENCE_NUM, message.sequence_num.encode_to_vec())];
+        if ours {
+            if let Some(status) = &message.remote_config_status {
+                sections.push((REMOTE_CONFIG_STATUS, status.encode_to_vec()));
+            }
+            if let Some(config) = &message.effective_config {
+                sections.push((EFFECTIVE_CONFIG, config.encode_to_vec()));
+            }
+            if let Some(statuses) = &message.package_statuses {
+                sections.push((PACKAGE_STATUSES, statuses.encode_to_vec()));
+            }
+        }
+        for (key, value) in sections {
+            if let Err(e) = store.save(key, &value) {
+                log::warn!("Unable to save {}: {}", key, e);
+            }
+        }
+    }
+
+    /// Records a message as delivered to the server
+    pub(crate) fn acknowledge(&mut self, message: &AgentToServer) {
+        if self.is_own_message(message) {
+            self.compression.acknowledge(message);
+        }
+        self.messages_sent += 1;
+    }
+
+    /// Records the request the server answered with an error, for `handle_server_error`
+    #[cfg(feature = "http")]
+    pub(crate) fn reject(&mut self, message: AgentToServer) {
+        self.rejected = Some(message);
+        self.messages_sent += 1;
+    }
+
+    /// Whether sending is paused because the server reported it is unavailable
+    pub(crate) fn is_held(&mut self) -> bool {
+        match self.hold_until {
+            Some(until) if Instant::now() < until => true,
+            Some(_) => {
+                self.hold_until = None;
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Acts on an error reported by the server and tells the application about it.
+    ///
+    /// Error responses do not identify the message they are about. Over HTTP they answer the
+    /// request they came with, which is dropped on a bad request and sent again once the server
+    /// is available. Over WebSocket several messages may be in flight, so no message is singled
+    /// out: a bad request is only reported and an unavailable server pauses sending.
+    fn handle_server_error(&mut self, response: &ServerErrorResponse) {
+        let error = ServerError::from(response);
+        let rejected = self.rejected.take();
+        match &error {
+            ServerError::BadRequest(_) => match rejected {
+                // Sending the same message again would fail the same way
+                Some(rejected) => log::warn!(
+                    "{}. Dropping message {}",
+                    error,
+                    rejected.sequence_num
+                ),
+                None => log::warn!("{}", error),
+            },
+            ServerError::Unavailable { retry_after, .. } => {
+                self.unavailable += 1;
+                let delay = retry_after.unwrap_or_else(|| {
+                    Duration::from_secs(2_u64.saturating_pow(self.unavailable))
+                        .min(MAX_UNAVAILABLE_DELAY)
+                });
+                log::warn!("{}. Sending again in {:?}", error, delay);
+                self.hold_until = Some(Instant::now() + delay);
+                // The server may have lost what it was told. Report every section again
+                self.compression.reset();
+                if let Some(rejected) = rejected {
+                    self.outbox.insert(0, rejected);
+                }
+            }
+            ServerError::Unknown(_) => log::warn!("{}", error),
+        }
+
+        self.record_error(&ApiClientError::new(line!(), &error.to_string()));
+        let mut func = self.callback.lock().unwrap();
+        func.on_server_error(&error);
+    }
+
+    /// Whether an outbound message reports our own status rather than that of a child agent
+    fn is_own_message(&self, message: &AgentToServer) -> bool {
+        message.instance_uid == self.instance_uid.to_wire()
+    }
+
+    /// Queues the full state of the supervisor and of every child agent for a new connection
+    pub(crate) fn enqueue_handshake(&mut self) -> Result<(), ApiClientError> {
+        self.enqueue_full_state()?;
+        let children = self.children.values().map(|child| child.state.clone());
+        self.outbox.extend(children);
+        Ok(())
+    }
+
+    /// Registers a child agent and queues its full state
+    pub(crate) fn register_child(&mut self, child: ChildAgent) -> Result<(), ApiClientError> {
+        if child.instance_uid == self.instance_uid {
+            return Err(ApiClientError::new(
+                line!(),
+                "A child agent can not share the supervisor instance_uid",
+            ));
+        }
+
+        let (uid, child) = Child::new(child);
+        log::info!("Registered child agent {}", uid);
+        self.outbox.push(child.state.clone());
+        self.children.insert(uid, child);
+        Ok(())
+    }
+
+    /// Removes a child agent and reports it as disconnected
+    pub(crate) fn deregister_child(&mut self, uid: &InstanceUid) -> Result<(), ApiClientError> {
+        let child = self.children.remove(uid).ok_or_else(|| {
+            ApiClientError::new(line!(), format!("Unknown child agent {}", uid).as_str())
+        })?;
+        log::info!("Deregistered child agent {}", uid);
+        self.outbox.push(AgentToServer {
+            instance_uid: child.state.instance_uid,
+            agent_disconnect: Some(AgentDisconnect::default()),
+            ..AgentToServer::default()
+        });
+        Ok(())
+    }
+
+    /// Merges status sections into the state of a child agent and queues them
+    pub(crate) fn update_child(
+        &mut self,
+        uid: &InstanceUid,
+        update: AgentToServer,
+    ) -> Result<(), ApiClientError> {
+        let child = self.children.get_mut(uid).ok_or_else(|| {
+            ApiClientError::new(line!(), format!("Unknown child agent {}", uid).as_str())
+        })?;
+        let message = child.update(update);
+        self.outbox.push(message);
+        Ok(())
+    }
+
+    /// Routes a message addressed to a child agent to its handler
+    fn dispatch_child(&mut self, uid: InstanceUid, msg: &ServerToAgent) {
+        let Some(mut child) = self.children.remove(&uid) else {
+            return;
+        };
+
+        // A server assigned identity applies to the child the message was addressed to
+        let mut current = uid;
+        if let Some(identification) = &msg.agent_identification {
+            match InstanceUid::from_wire(&identification.new_instance_uid) {
+                Ok(assigned) if assigned != uid => {
+                    child.rename(&uid, &assigned);
+                    current = assigned;
+                }
+                Ok(_) => {}
+                Err(e) => log::warn!("Ignoring server assigned child instance_uid: {}", e),
+            }
+        }
+
+        let replies = child.dispatch(msg);
+        self.outbox.extend(replies);
+        self.children.insert(current, child);
+    }
+
+    /// Queues the full agent state, bypassing status compression
+    pub(crate) fn enqueue_full_state(&mut self) -> Result<(), ApiClientError> {
+        let state = self.get_status()?;
+        self.compression.reset();
+        self.outbox.push(state);
+        Ok(())
+    }
+
+    /// Replaces our own health and queues it for the server. The start time and component
+    /// health map are kept unless `health` carries them, the status time defaults to now.
+    pub(crate) fn set_health(&mut self, mut health: ComponentHealth) -> Result<(), ApiClientError> {
+        let mut state = self.get_status()?;
+        let current = state.health.take().unwrap_or_else(defaults::agent_health);
+        if health.start_time_unix_nano == 0 {
+            health.start_time_unix_nano = current.start_time_unix_nano;
+        }
+        if health.component_health_map.is_empty() {
+            health.component_health_map = current.component_health_map;
+        }
+        if health.status_time_unix_nano == 0 {
+            health.status_time_unix_nano = crate::get_time_nanos!() as u64;
+        }
+        state.health = Some(health);
+        *self.agent_state.borrow_mut() = Some(state);
+
+        self.enqueue_health();
+        Ok(())
+    }
+
+    /// Sets the health of a managed component in `component_health_map`, or removes the
+    /// component when `health` is `None`, and queues the result for the server
+    pub(crate) fn set_component_health(
+        &mut self,
+        name: &str,
+        health: Option<ComponentHealth>,
+    ) -> Result<(), ApiClientError> {
+        let mut state = self.get_status()?;
+        let own = state.health.get_or_insert_with(defaults::agent_health);
+        match health {
+            Some(mut health) => {
+                if health.status_time_unix_nano == 0 {
+                    health.status_time_unix_nano = crate::get_time_nanos!() as u64;
+                }
+                own.component_health_map.insert(name.to_string(), health);
+            }
+            None => {
+                own.component_health_map.remove(name);
+            }
+        }
+        *self.agent_state.borrow_mut() = Some(state);
+
+        self.enqueue_health();
+        Ok(())
+    }
+
+    /// Queues our current health for the server if we report health
+    fn enqueue_health(&mut self) {
+        if !self.has_capability(Capabilities::REPORTS_HEALTH) {
+            return;
+        }
+        let health = self
+            .agent_state
+            .borrow()
+            .as_ref()
+            .and_then(|state| state.health.clone());
+        self.outbox.push(AgentToServer {
+            instance_uid: self.instance_uid.to_wire(),
+            health,
+            ..AgentToServer::default()
+        });
+    }
+
+    pub(crate) fn get_status(&mut self) -> Result<AgentToServer, ApiClientError> {
+        // Populate an initial state if it doesnt yet exist
+        if self.agent_state.borrow().is_none() {
+            // Pick up where a previous run left off
+            let remote_config_status = self
+                .restore(REMOTE_CONFIG_STATUS)
+                .unwrap_or_else(defaults::remote_config_status);
+            let package_statuses = self.package_statuses();
+
+            // Get our client configuration data
+            let mut func = self.callback.lock().unwrap();
+            let config_map = match func.get_configuration() {
+                Ok(reply) => reply,
+                Err(e) => {
+                    log::warn!("API callback error: {}", e);
+                    None
+                }
+            };
+
+            // Get agent capabilities
+            let (capabilities, flags) = func.get_features();
+            for problem in capabilities.inconsistencies() {
+                log::warn!("Capability check: {}", problem);
+            }
+            if capabilities.contains(Capabilities::REPORTS_EFFECTIVE_CONFIG) && config_map.is_none()
+            {
+                log::warn!(
+                    "Capability check: ReportsEffectiveConfig is set but get_configuration() reported no configuration"
+                );
+            }
+
+            // Get the agent description, seeded with our identity
+            let description = DescriptionBuilder::new(
+                self.settings.name.as_str(),
+                self.settings.version.as_str(),
+                self.settings.instance_id.as_str(),
+            )
+            .with_default_detectors();
+            let agent_description = func.get_description(description);
+
+            // Get the component inventory unless the application already set one
+            if capabilities.contains(Capabilities::REPORTS_AVAILABLE_COMPONENTS)
+                && self.available_components.is_none()
+            {
+                self.available_components = func.get_available_components().map(with_hash);
+            }
+            let available_components = self
+                .available_components
+                .as_ref()
+                .filter(|_| capabilities.contains(Capabilities::REPORTS_AVAILABLE_COMPONENTS))
+                .map(|inventory| AvailableComponents {
+                    components: HashMap::new(),
+                    hash: inventory.hash.clone(),
+                });
+
+            // The configuration reported by the application wins over the saved one
+            let effective_config = match config_map {
+                Some(config_map) => EffectiveConfig {
+                    config_map: Some(config_map),
+                },
+                None => self
+                    .restore(EFFECTIVE_CONFIG)
+                    .unwrap_or_else(defaults::effective_config),
+            };
+
+            *self.agent_state.borrow_mut() = Some(AgentToServer {
+                instance_uid: self.instance_uid.to_wire(),
+                sequence_num: 0, // Populated on send
+                capabilities: capabilities.bits(),
+                flags: flags.bits(),
+
+                agent_description: Some(agent_description),
+                health: Some(defaults::agent_health()),
+                effective_config: Some(effective_config),
+                remote_config_status: Some(remote_config_status),
+                package_statuses: Some(package_statuses),
+                agent_disconnect: None,
+                connection_settings_request: None,
+                custom_capabilities: Some(self.custom_capabilities())
+                    .filter(|custom| !custom.capabilities.is_empty()),
+                custom_message: None,
+                available_components,
+            });
+        }
+
+        self.agent_state
+            .borrow()
+            .clone()
+            .ok_or_else(|| ApiClientError::new(line!(), "Agent state unavailable"))
+    }
+
+    /// Switches to a new instance_uid assigned by the server and notifies the application
+    fn adopt_instance_uid(&mut self, uid: InstanceUid) {
+        if uid == self.instance_uid {
+            return;
+        }
+
+        log::info!(
+            "Server assigned instance_uid {} (was {})",
+            uid,
+            self.instance_uid
+        );
+        let previous = std::mem::replace(&mut self.instance_uid, uid);
+        self.settings.instance_id = uid.to_string();
+        if let Some(identity) = self.identity.as_ref() {
+            if let Err(e) = identity.save(&uid) {
+                log::warn!("Unable to save the server assigned instance_uid: {}", e);
+            }
+        }
+        if let Some(state) = self.agent_state.borrow_mut().as_mut() {
+            state.instance_uid = uid.to_wire();
+            // Keep service.instance.id in step and let the server know about the new description
+            if let Some(description) = state.agent_description.as_mut() {
+                let instance_id = attribute("service.instance.id", uid.to_string());
+                for kv in description
+                    .identifying_attributes
+                    .iter_mut()
+                    .filter(|kv| kv.key == instance_id.key)
+                {
+                    *kv = instance_id.clone();
+                }
+                self.outbox.push(AgentToServer {
+                    instance_uid: uid.to_wire(),
+                    agent_description: Some(description.clone()),
+                    ..AgentToServer::default()
+                });
+            }
+        }
+
+        let mut func = self.callback.lock().unwrap();
+        func.on_instance_uid_changed(&previous, &uid);
+    }
+
+    /// Replaces the effective config in the agent state and queues it for the server
+    pub(crate) fn set_effective_config(
+        &mut self,
+        config_map: Option<AgentConfigMap>,
+    ) -> Result<(), ApiClientError> {
+        let effective_config = EffectiveConfig { config_map };
+        let mut state = self.get_status()?;
+        state.effective_config = Some(effective_config.clone());
+        *self.agent_state.borrow_mut() = Some(state);
+
+        self.outbox.push(AgentToServer {
+            instance_uid: self.instance_uid.to_wire(),
+            effective_config: Some(effective_config),
+            ..AgentToServer::default()
+        });
+        Ok(())
+    }
+
+    /// Asks the application for its current configuration and reports it if it changed
+    pub(crate) fn refresh_effective_config(&mut self) -> Result<(), ApiClientError> {
+        let config_map = {
+            let mut func = self.callback.lock().unwrap();
+            func.get_configuration()?
+        };
+        let current = self.get_status()?.effective_config;
+        if current.as_ref().map(|config| &config.config_map) != Some(&config_map) {
+            self.set_effective_config(config_map)?;
+        }
+        Ok(())
+    }
+
+    /// Records a new remote config status in the agent state and queues it for the server
+    fn report_remote_config_status(
+        &mut self,
+        hash: &[u8],
+        status: RemoteConfigStatuses,
+        error_message: String,
+    ) -> Result<(), ApiClientError> {
+        let remote_config_status = RemoteConfigStatus {
+            last_remote_config_hash: hash.to_vec(),
+            status: status.into(),
+            error_message,
+        };
+        let mut state = self.get_status()?;
+        state.remote_config_status = Some(remote_config_status.clone());
+        *self.agent_state.borrow_mut() = Some(state);
+
+        self.outbox.push(AgentToServer {
+            instance_uid: self.instance_uid.to_wire(),
+            remote_config_status: Some(remote_config_status),
+            ..AgentToServer::default()
+        });
+        Ok(())
+    }
+
+    /// Package statuses for the initial agent state. A package manager that has not installed
+    /// anything yet takes over the statuses saved by a previous run.
+    fn package_statuses(&mut self) -> PackageStatuses {
+        let saved = self.restore(PACKAGE_STATUSES);
+        #[cfg(feature = "packages")]
+        if let Some(manager) = self.packages.as_mut() {
+            if let Some(saved) = saved.filter(|_| manager.statuses() == &PackageStatuses::default())
+            {
+                manager.restore(saved);
+            }
+            return manager.statuses().clone();
+        }
+        saved.unwrap_or_else(defaults::package_statuses)
+    }
+
+    /// Installs package offers with `manager` from now on
+    #[cfg(feature = "packages")]
+    pub(crate) fn set_package_manager(&mut self, manager: PackageManager) {
+        let statuses = manager.statuses().clone();
+        self.packages = Some(manager);
+        if let Some(state) = self.agent_state.borrow_mut().as_mut() {
+            state.package_statuses = Some(statuses);
+        }
+    }
+
+    /// Records new package statuses in the agent state and queues them for the server
+    #[cfg(feature = "packages")]
+    fn report_package_statuses(&mut self, statuses: PackageStatuses) -> Result<(), ApiClientError> {
+        let mut state = self.get_status()?;
+        state.package_statuses = Some(statuses.clone());
+        *self.agent_state.borrow_mut() = Some(state);
+
+        if self.has_capability(Capabilities::REPORTS_PACKAGE_STATUSES) {
+            self.outbox.push(AgentToServer {
+                instance_uid: self.instance_uid.to_wire(),
+                package_statuses: Some(statuses),
+                ..AgentToServer::default()
+            });
+        }
+        Ok(())
+    }
+
+    /// Hands a remote config reported as applying to the application, starts a scheduled agent
+    /// restart or reports the outcome of a finished one, otherwise installs the next pending
+    /// package. Each is followed by the resulting status.
+    ///
+    /// Returns:
+    ///
+    /// Whether any deferred work was done
+    pub(crate) async fn run_deferred(&mut self) -> bool {
+        if let Some(msg) = self.remote_config.take() {
+            if let Err(e) = self.deliver_remote_config(&msg) {
+                log::warn!("Unable to report remote config status: {}", e);
+            }
+            return true;
+        }
+        #[cfg(feature = "launcher")]
+        if self.restart.as_ref().is_some_and(|r| r.is_finished()) {
+            self.complete_restart().await;
+            return true;
+        }
+        #[cfg(feature = "launcher")]
+        if self.restart_pending && self.restart.is_none() {
+            self.restart_agent();
+            return true;
+        }
+
+        #[cfg(feature = "packages")]
+        if let Some(manager) = self.packages.as_mut() {
+            if let Some(statuses) = manager.install_next().await {
+                if let Err(e) = self.report_package_statuses(statuses) {
+                    log::warn!("Unable to report package status: {}", e);
+                }
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Runs the built-in handler of a command, if there is one.
+    ///
+    /// Returns:
+    ///
+    /// Whether the command was handled
+    fn handle_command(&mut self, _command: &ServerToAgentCommand) -> bool {
+        #[cfg(feature = "launcher")]
+        if _command.r#type == CommandType::Restart as i32 && self.restart_task.is_some() {
+            log::info!("Restart requested by server");
+            self.schedule_restart();
+            return true;
+        }
+        false
+    }
+
+    /// Handles a restart command with the restart task from now on
+    #[cfg(feature = "launcher")]
+    pub(crate) fn set_restart_task(&mut self, task: Arc<Task>) {
+        self.restart_task = Some(task);
+    }
+
+    /// Writes other connection offers with `connections` from now on
+    #[cfg(feature = "connections")]
+    pub(crate) fn set_other_connections(&mut self, connections: OtherConnections) {
+        let mut connections = connections;
+        if let Some(hash) = self.restore::<Vec<u8>>(OTHER_CONNECTIONS) {
+            connections.restore(hash);
+        }
+        self.other_connections = Some(connections);
+    }
+
+    /// Writes the other connection offers for the agent and has the agent reload them, through
+    /// the restart task when set or else through the application callback
+    #[cfg(feature = "connections")]
+    fn apply_other_connections(&mut self, offers: &ConnectionSettingsOffers) {
+        let Some(connections) = self.other_connections.as_mut() else {
+            return;
+        };
+        match connections.apply(offers) {
+            Ok(changed) => {
+                let hash = connections.hash().to_vec();
+                let path = connections.path().to_path_buf();
+                if let Some(store) = self.state_store.as_mut() {
+                    if let Err(e) = store.save(OTHER_CONNECTIONS, &hash.encode_to_vec()) {
+                        log::warn!("Unable to save {}: {}", OTHER_CONNECTIONS, e);
+                    }
+                }
+                if !changed {
+                    return;
+                }
+                #[cfg(feature = "launcher")]
+                if self.restart_task.is_some() {
+                    log::info!("Other connection settings changed. Reloading the agent");
+                    self.schedule_restart();
+                    return;
+                }
+                let mut func = self.callback.lock().unwrap();
+                func.on_other_connections_changed(&path);
+            }
+            Err(e) => self.reject_settings(e),
+        }
+    }
+
+    /// Exports our own logs through `exporter` to the destinations offered from now on
+    #[cfg(feature = "logs")]
+    pub(crate) fn set_logs_exporter(&mut self, exporter: LogsExporter) {
+        self.logs = Some(exporter);
+    }
+
+    /// Reports the agent as unhealthy and defers the restart so the report goes out first
+    #[cfg(feature = "launcher")]
+    fn schedule_restart(&mut self) {
+        self.restart_pending = true;
+        self.report_health(ComponentHealth {
+            healthy: false,
+            status: "restarting".to_string(),
+            ..ComponentHealth::default()
+        });
+    }
+
+    /// Restarts the supervised task in the background. Stopping the agent takes seconds, so the
+    /// poll loop keeps serving the server meanwhile and reports the outcome once it is known
+    #[cfg(feature = "launcher")]
+    fn restart_agent(&mut self) {
+        self.restart_pending = false;
+        let Some(task) = self.restart_task.clone() else {
+            return;
+        };
+        self.restart = Some(tokio::task::spawn_blocking(move || {
+            if !task.restart() {
+                return Err("Failed to relaunch the agent".to_string());
+            }
+            // An agent failing on startup, e.g. on a bad configuration, exits right away
+            std::thread::sleep(RESTART_SETTLE_TIME);
+            if task.is_running() {
+                return Ok(());
+            }
+            Err(match task.exit_status() {
+                Some(status) => format!("Agent exited after restart: {:?}", status),
+                None => "Agent is not running after restart".to_string(),
+            })
+        }));
+    }
+
+    /// Sends the status resulting from a finished restart
+    #[cfg(feature = "launcher")]
+    async fn complete_restart(&mut self) {
+        let Some(restart) = self.restart.take() else {
+            return;
+        };
+        let outcome = restart
+            .await
+            .unwrap_or_else(|e| Err(format!("Agent restart failed: {}", e)));
+        match outcome {
+            Ok(()) => {
+                log::info!("Agent restarted");
+                self.report_health(ComponentHealth {
+                    healthy: true,
+                    status: "running".to_string(),
+                    start_time_unix_nano: crate::get_time_nanos!() as u64,
+                    ..ComponentHealth::default()
+                });
+                if let Err(e) = self.enqueue_full_state() {
+                    log::warn!("Unable to report status after restart: {}", e);
+                }
+            }
+            Err(e) => {
+                log::error!("{}", e);
+                self.report_health(ComponentHealth {
+                    healthy: false,
+                    status: "restart failed".to_string(),
+                    last_error: e,
+                    ..ComponentHealth::default()
+                });
+            }
+        }
+    }
+
+    /// Updates our own health, logging failures
+    #[cfg(feature = "launcher")]
+    fn report_health(&mut self, health: ComponentHealth) {
+        if let Err(e) = self.set_health(health) {
+            log::warn!("Unable to update health: {}", e);
+        }
+    }
+
+    /// Reports a new remote config as applying. The config is handed to the application once
+    /// that report went out, see `deliver_remote_config`. A config whose hash was already applied
+    /// is not delivered again, and a config still waiting is replaced by a newer one.
+    fn apply_remote_config(
+        &mut self,
+        msg: &ServerToAgent,
+        remote_config: &AgentRemoteConfig,
+    ) -> Result<(), ApiClientError> {
+        let hash = remote_config.config_hash.as_slice();
+        let already_applied = self
+            .get_status()?
+            .remote_config_status
+            .is_some_and(|current| {
+                !hash.is_empty()
+                    && current.last_remote_config_hash == hash
+                    && current.status == RemoteConfigStatuses::Applied as i32
+            });
+        if already_applied {
+            log::debug!("Remote config already applied. Skipping");
+            return Ok(());
+        }
+
+        self.report_remote_config_status(hash, RemoteConfigStatuses::Applying, "".to_string())?;
+        self.remote_config = Some(msg.clone());
+        Ok(())
+    }
+
+    /// Hands a remote config to the application and reports it as applied or failed depending
+    /// on the outcome of the callback
+    fn deliver_remote_config(&mut self, msg: &ServerToAgent) -> Result<(), ApiClientError> {
+        let hash = msg
+            .remote_config
+            .as_ref()
+            .map(|remote_config| remote_config.config_hash.clone())
+            .unwrap_or_default();
+        let outcome = {
+            let mut func = self.callback.lock().unwrap();
+            func.on_agent_remote_config(msg)
+        };
+        match outcome {
+            Ok(reply) => {
+                match reply
+                    .as_ref()
+                    .and_then(|reply| reply.remote_config_status.clone())
+                {
+                    // The reply reports the outcome itself
+                    Some(status) => {
+                        let mut state = self.get_status()?;
+                        state.remote_config_status = Some(status);
+                        *self.agent_state.borrow_mut() = Some(state);
+                    }
+                    None => self.report_remote_config_status(
+                        &hash,
+                        RemoteConfigStatuses::Applied,
+                        "".to_string(),
+                    )?,
+                }
+                // Re-read the configuration unless the reply already reports the new one
+                let reports_config = reply
+                    .as_ref()
+                    .is_some_and(|reply| reply.effective_config.is_some());
+                if let Some(reply) = reply {
+                    self.outbox.push(reply);
+                }
+                if !reports_config {
+                    if let Err(e) = self.refresh_effective_config() {
+                        log::warn!("API callback error: {}", e);
+                    }
+                }
+            }
+            Err(e) => {
+                log::warn!("API callback error: {}", e);
+                self.report_remote_config_status(
+                    &hash,
+                    RemoteConfigStatuses::Failed,
+                    e.to_string(),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Routes an inbound message to the relevant callbacks and queues their replies
+    pub(crate) fn dispatch(&mut self, msg: &ServerToAgent) -> Result<(), ApiClientError> {
+        log::trace!("[ServerToAgent]\n{:#?}", msg);
+        self.messages_received += 1;
+        if let Some(uid) = InstanceUid::from_wire(&msg.instance_uid)
+            .ok()
+            .filter(|uid| self.children.contains_key(uid))
+        {
+            self.dispatch_child(uid, msg);
+            return Ok(());
+        }
+
+        // The instance_uid isnt us or a registered child. None of it applies to the supervisor,
+        // let the application decide
+        let addressed_to_us = msg.instance_uid.is_empty()
+            || InstanceUid::from_wire(&msg.instance_uid).is_ok_and(|uid| uid == self.instance_uid);
+        if !addressed_to_us {
+            log::debug!("Message for an unknown instance_uid");
+            let mut func = self.callback.lock().unwrap();
+            match func.on_health_check(msg) {
+                Ok(Some(reply)) => self.outbox.push(reply),
+                Ok(None) => {}
+                Err(e) => {
+                    log::warn!("API callback error: {}", e);
+                }
+            };
+            return Ok(());
+        }
+
+        // Adopt a server assigned identity before anything else
+        if let Some(identification) = &msg.agent_identification {
+            match InstanceUid::from_wire(&identification.new_instance_uid) {
+                Ok(uid) => self.adopt_instance_uid(uid),
+                Err(e) => log::warn!("Ignoring server assigned instance_uid: {}", e),
+            }
+        }
+
+        if let Some(command) = msg
+            .command
+            .as_ref()
+            .filter(|_| self.accepts(Capabilities::ACCEPTS_RESTART_COMMAND, "command"))
+        {
+            log::trace!("Received a command: {:?}", command);
+            if !self.handle_command(command) {
+                let mut func = self.callback.lock().unwrap();
+                match func.on_command(msg) {
+                    Ok(Some(reply)) => self.outbox.push(reply),
+                    Ok(None) => {}
+                    Err(e) => {
+                        log::warn!("API callback error: {}", e);
+                    }
+                };
+            }
+        }
+
+        // Act on upstream errors and relay them to the client
+        if let Some(response) = &msg.error_response {
+            {
+                let mut func = self.callback.lock().unwrap();
+                func.on_error(msg);
+            }
+            self.handle_server_error(response);
+        } else {
+            self.unavailable = 0;
+        }
+
+        if let Some(custom_message) = &msg.custom_message {
+            self.dispatch_custom_message(custom_message);
+        }
+
+        if msg.flags & (ServerToAgentFlags::ReportAvailableComponents as u64) != 0
+            && self.accepts(
+                Capabilities::REPORTS_AVAILABLE_COMPONENTS,
+                "available components request",
+            )
+        {
+            self.enqueue_available_components();
+        }
+
+        // Check and report full state
+        if msg.flags & (ServerToAgentFlags::ReportFullState as u64) != 0 {
+            self.enqueue_full_state()?;
+        }
+
+        if let Some(agent_rc) = msg
+            .remote_config
+            .as_ref()
+            .filter(|_| self.accepts(Capabilities::ACCEPTS_REMOTE_CONFIG, "remote config"))
+        {
+            log::trace!("Received a remote config: {:?}", agent_rc);
+            self.apply_remote_config(msg, agent_rc)?;
+        }
+
+        if let Some(_connection_settings_offers) = &msg.connection_settings {
+            if let Some(opamp) = _connection_settings_offers
+                .opamp
+                .as_ref()
+                .filter(|_| self.has_capability(Capabilities::ACCEPTS_OPAMP_CONNECTION_SETTINGS))
+            {
+                if let Err(e) = self.apply_opamp_settings(opamp) {
+                    self.reject_settings(e);
+                }
+            }
+
+            // Telemetry certificates are kept for the exporters configured by the application
+            let telemetry = [
+                (
+                    "own_metrics",
+                    &_connection_settings_offers.own_metrics,
+                    Capabilities::REPORTS_OWN_METRICS,
+                ),
+                (
+                    "own_traces",
+                    &_connection_settings_offers.own_traces,
+                    Capabilities::REPORTS_OWN_TRACES,
+                ),
+                (
+                    "own_logs",
+                    &_connection_settings_offers.own_logs,
+                    Capabilities::REPORTS_OWN_LOGS,
+                ),
+            ];
+            for (name, offer, capability) in telemetry {
+                let certificate = offer.as_ref().and_then(|o| o.certificate.as_ref());
+                if let Some(certificate) = certificate.filter(|_| self.has_capability(capability)) {
+                    let stored = self
+                        .settings
+                        .key_store
+                        .as_ref()
+                        .and_then(|key_store| tls::load(key_store, name));
+                    if stored.as_ref() == Some(certificate) {
+                        log::debug!("Offered {} certificate unchanged", name);
+                        continue;
+                    }
+                    match tls::validate(certificate) {
+                        Ok(()) => self.store_certificate(name, certificate),
+                        Err(e) => self.reject_settings(e),
+                    }
+                }
+            }
+
+            #[cfg(feature = "metrics")]
+            if let Some(offer) = _connection_settings_offers
+                .own_metrics
+                .as_ref()
+                .filter(|_| self.has_capability(Capabilities::REPORTS_OWN_METRICS))
+            {
+                let resource = self.own_telemetry_resource();
+                if let Err(e) = self.metrics.configure(offer, resource) {
+                    self.reject_settings(e);
+                }
+            }
+
+            #[cfg(feature = "connections")]
+            if (!_connection_settings_offers.other_connections.is_empty()
+                || !_connection_settings_offers.hash.is_empty())
+                && self.has_capability(Capabilities::ACCEPTS_OTHER_CONNECTION_SETTINGS)
+            {
+                self.apply_other_connections(_connection_settings_offers);
+            }
+
+            #[cfg(feature = "logs")]
+            if let Some(offer) = _connection_settings_offers
+                .own_logs
+                .as_ref()
+                .filter(|_| self.has_capability(Capabilities::REPORTS_OWN_LOGS))
+            {
+                let resource = self.own_telemetry_resource();
+                let configured = self
+                    .logs
+                    .as_ref()
+                    .map(|logs| logs.configure(offer, resource));
+                if let Some(Err(e)) = configured {
+                    self.reject_settings(e);
+                }
+            }
+
+            // Offers we did not declare a capability for are ignored harmlessly
+            let offers = [
+                (
+                    _connection_settings_offers.opamp.is_some(),
+                    Capabilities::ACCEPTS_OPAMP_CONNECTION_SETTINGS,
+                    "OpAMP connection settings",
+                ),
+                (
+                    _connection_settings_offers.own_metrics.is_some(),
+                    Capabilities::REPORTS_OWN_METRICS,
+                    "own metrics connection settings",
+                ),
+                (
+                    _connection_settings_offers.own_traces.is_some(),
+                    Capabilities::REPORTS_OWN_TRACES,
+                    "own traces connection settings",
+                ),
+                (
+                    _connection_settings_offers.own_logs.is_some(),
+                    Capabilities::REPORTS_OWN_LOGS,
+                    "own logs connection settings",
+                ),
+                (
+                    !_connection_settings_offers.other_connections.is_empty(),
+                    Capabilities::ACCEPTS_OTHER_CONNECTION_SETTINGS,
+                    "other connection settings",
+                ),
+            ];
+            let accepted = offers
+                .iter()
+                .filter(|(offered, capability, what)| *offered && self.accepts(*capability, what))
+                .count();
+
+            if accepted > 0 {
+                let mut func = self.callback.lock().unwrap();
+                // TODO: Send specific type of this callback as an enum
+                match func.on_connection_settings_offers(msg) {
+                    Ok(Some(reply)) => self.outbox.push(reply),
+                    Ok(None) => {}
+                    Err(e) => {
+                        log::warn!("API callback error: {}", e);
+                    }
+                };
+            }
+        }
+
+        if let Some(packages_available) = msg
+            .packages_available
+            .as_ref()
+            .filter(|_| self.accepts(Capabilities::ACCEPTS_PACKAGES, "packages available"))
+        {
+            #[cfg(feature = "packages")]
+            if let Some(manager) = self.packages.as_mut() {
+                if let Some(statuses) = manager.offer(packages_available) {
+                    self.report_package_statuses(statuses)?;
+                }
+                return Ok(());
+            }
+
+            log::trace!("Received packages available: {:?}", packages_available);
+            let mut func = self.callback.lock().unwrap();
+            match func.on_packages_available(msg) {
+                Ok(Some(reply)) => self.outbox.push(reply),
+                Ok(None) => {}
+                Err(e) => {
+                    log::warn!("API callback error: {}", e);
+                }
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the component inventory and queues its hash for the server
+    pub(crate) fn set_available_components(
+        &mut self,
+        components: AvailableComponents,
+    ) -> Result<(), ApiClientError> {
+        let components = with_hash(components);
+        let hash = components.hash.clone();
+        self.available_components = Some(components);
+
+        // Populates the initial state, which picks up the inventory
+        self.get_status()?;
+        if self.has_capability(Capabilities::REPORTS_AVAILABLE_COMPONENTS) {
+            self.outbox.push(AgentToServer {
+                instance_uid: self.instance_uid.to_wire(),
+                available_components: Some(AvailableComponents {
+                    components: HashMap::new(),
+                    hash,
+                }),
+                ..AgentToServer::default()
+            });
+        }
+        Ok(())
+    }
+
+    /// Queues the full component inventory the server asked for
+    fn enqueue_available_components(&mut self) {
+        if self.available_components.is_none() {
+            let mut func = self.callback.lock().unwrap();
+            self.available_components = func.get_available_components().map(with_hash);
+        }
+        match self.available_components.clone() {
+            Some(inventory) => self.outbox.push(AgentToServer {
+                instance_uid: self.instance_uid.to_wire(),
+                available_components: Some(inventory),
+                ..AgentToServer::default()
+            }),
+            None => log::warn!("Server asked for available components but there is no inventory"),
+        }
+    }
+
+    /// Declares a custom capability and queues the updated list for the server
+    pub(crate) fn register_custom_capability(
+        &mut self,
+        capability: &str,
+        handler: Box<dyn CustomMessageHandler + Send + Sync>,
+    ) -> Result<(), ApiClientError> {
+        if capability.is_empty() {
+            return Err(ApiClientError::new(line!(), "Empty custom capability"));
+        }
+        self.custom_handlers.insert(capability.to_string(), handler);
+        self.report_custom_capabilities()
+    }
+
+    /// Withdraws a custom capability and queues the updated list for the server
+    pub(crate) fn deregister_custom_capability(
+        &mut self,
+        capability: &str,
+    ) -> Result<(), ApiClientError> {
+        if self.custom_handlers.remove(capability).is_none() {
+            return Err(ApiClientError::new(
+                line!(),
+                format!("Unknown custom capability {}", capability).as_str(),
+            ));
+        }
+        self.report_custom_capabilities()
+    }
+
+    /// The declared custom capabilities, in a stable order
+    fn custom_capabilities(&self) -> CustomCapabilities {
+        let mut capabilities: Vec<String> = self.custom_handlers.keys().cloned().collect();
+        capabilities.sort();
+        CustomCapabilities { capabilities }
+    }
+
+    /// Records the declared custom capabilities in the agent state and queues them for the server
+    fn report_custom_capabilities(&mut self) -> Result<(), ApiClientError> {
+        let custom_capabilities = self.custom_capabilities();
+        let mut state = self.get_status()?;
+        state.custom_capabilities = Some(custom_capabilities.clone());
+        *self.agent_state.borrow_mut() = Some(state);
+
+        self.outbox.push(AgentToServer {
+            instance_uid: self.instance_uid.to_wire(),
+            custom_capabilities: Some(custom_capabilities),
+            ..AgentToServer::default()
+        });
+        Ok(())
+    }
+
+    /// Queues a custom message. As the specification asks, a new custom message is refused
+    /// while the previous one has not been sent yet
+    pub(crate) fn send_custom_message(
+        &mut self,
+        message: CustomMessage,
+    ) -> Result<(), ApiClientError> {
+        if !self.custom_handlers.contains_key(&message.capability) {
+            return Err(ApiClientError::new(
+                line!(),
+                format!("Unknown custom capability {}", message.capability).as_str(),
+            ));
+        }
+        if self.custom_message_pending() {
+            return Err(ApiClientError::new(
+                line!(),
+                "A custom message is still pending",
+            ));
+        }
+
+        self.outbox.push(AgentToServer {
+            instance_uid: self.instance_uid.to_wire(),
+            custom_message: Some(message),
+            ..AgentToServer::default()
+        });
+        Ok(())
+    }
+
+    /// Whether a custom message is waiting to be sent
+    pub(crate) fn custom_message_pending(&self) -> bool {
+        self.outbox
+            .iter()
+            .any(|message| message.custom_message.is_some())
+    }
+
+    /// Hands a custom message to the handler of its capability and queues the reply. Like custom
+    /// messages of the application, a reply is dropped while another custom message is pending
+    fn dispatch_custom_message(&mut self, message: &CustomMessage) {
+        let handler = match self.custom_handlers.get_mut(&message.capability) {
+            Some(handler) => handler,
+            None => {
+                log::warn!(
+                    "Rejecting custom message from server: custom capability {} not declared",
+                    message.capability
+                );
+                return;
+            }
+        };
+        match handler.on_custom_message(message) {
+            Ok(Some(_)) if self.custom_message_pending() => {
+                log::warn!(
+                    "Dropping reply to custom message {}: a custom message is still pending",
+                    message.r#type
+                );
+            }
+            Ok(Some(reply)) => self.outbox.push(AgentToServer {
+                instance_uid: self.instance_uid.to_wire(),
+                custom_message: Some(reply),
+                ..AgentToServer::default()
+            }),
+            Ok(None) => {}
+            Err(e) => {
+                log::warn!("Custom message handler error: {}", e);
+            }
+        }
+    }
+
+    /// Attributes identifying the agent in the telemetry it reports about itself
+    #[cfg(any(feature = "metrics", feature = "logs"))]
+    fn own_telemetry_resource(&self) -> Vec<KeyValue> {
+        self.agent_state
+            .borrow()
+            .as_ref()
+            .and_then(|state| state.agent_description.as_ref())
+            .map(|description| description.identifying_attributes.clone())
+            .unwrap_or_default()
+    }
+
+    /// Whether the application advertised `capability`
+    pub(crate) fn has_capability(&self, capability: Capabilities) -> bool {
+        self.agent_state.borrow().as_ref().is_some_and(|state| {
+            Capabilities::from_bits_truncate(state.capabilities).contains(capability)
+        })
+    }
+
+    /// Checks an inbound message type against our capabilities, rejecting it when undeclared
+    fn accepts(&self, capability: Capabilities, what: &str) -> bool {
+        let accepted = self.has_capability(capability);
+        if !accepted {
+            log::warn!(
+                "Rejecting {} from server: capability {:?} not declared",
+                what,
+                capability
+            );
+        }
+        accepted
+    }
+
+    /// Builds an empty status message, used to poll the server and as a heartbeat
+    pub(crate) fn heartbeat(&self) -> AgentToServer {
+        AgentToServer {
+            instance_uid: self.instance_uid.to_wire(),
+            ..AgentToServer::default()
+        }
+    }
+
+    /// Call the on_loop for the client to communicate any state to the server
+    pub(crate) fn run_loop(&mut self) {
+        let mut func = self.callback.lock().unwrap();
+        match func.on_loop() {
+            Ok(Some(reply)) => self.outbox.push(reply),
+            Ok(None) => {}
+            Err(e) => {
+                log::warn!("API on_loop error: {}", e);
+            }
+        };
+    }
+}
+
+/// Checks an offered header for characters that are not allowed in HTTP headers
+fn validate_header(header: &Header) -> Result<(String, String), ApiClientError> {
+    let valid_name = !header.key.is_empty()
+        && header
+            .key
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    let valid_value = header
+        .value
+        .bytes()
+        .all(|b| b == b'\t' || (b >= 0x20 && b != 0x7f));
+    if !valid_name || !valid_value {
+        return Err(ApiClientError::new(
+            line!(),
+            format!("Invalid header {}", header.key).as_str(),
+        ));
+    }
+    Ok((header.key.clone(), header.value.clone()))
+}
+
+/// Fills in the hash of an inventory that has none
+fn with_hash(mut inventory: AvailableComponents) -> AvailableComponents {
+    if inventory.hash.is_empty() {
+        inventory.hash = components_hash(&inventory.components);
+    }
+    inventory
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::ChildCallbacks;
+    use crate::opamp::capabilities::AgentFlags;
+    use std::path::PathBuf;
+
+    fn header(key: &str, value: &str) -> Header {
+        Header {
+            key: key.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    /// Answers every custom message with a message of type `reply`
+    struct Echo;
+
+    impl CustomMessageHandler for Echo {
+        fn on_custom_message(
+            &mut self,
+            message: &CustomMessage,
+        ) -> Result<Option<CustomMessage>, ApiClientError> {
+            Ok(Some(CustomMessage {
+                r#type: "reply".to_string(),
+                ..message.clone()
+            }))
+        }
+    }
+
+    /// Callbacks recording which of them the session called
+    #[derive(Clone, Default)]
+    struct Recorder {
+        capabilities: Capabilities,
+        calls: Arc<Mutex<Vec<&'static str>>>,
+        fail_remote_config: bool,
+    }
+
+    impl Recorder {
+        fn record(&self, call: &'static str) -> Result<Option<AgentToServer>, ApiClientError> {
+            self.calls.lock().unwrap().push(call);
+            Ok(None)
+        }
+
+        fn calls(&self) -> Vec<&'static str> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    impl ApiCallbacks for Recorder {
+        fn get_configuration(&mut self) -> Result<Option<AgentConfigMap>, ApiClientError> {
+            Ok(None)
+        }
+        fn get_features(&mut self) -> (Capabilities, AgentFlags) {
+            (self.capabilities, AgentFlags::empty())
+        }
+        fn on_loop(&mut self) -> Result<Option<AgentToServer>, ApiClientError> {
+            Ok(None)
+        }
+        fn on_error(&mut self, _inbound: &ServerToAgent) {
+            let _ = self.record("on_error");
+        }
+        fn on_health_check(
+            &mut self,
+            _inbound: &ServerToAgent,
+        ) -> Result<Option<AgentToServer>, ApiClientError> {
+            self.record("on_health_check")
+        }
+        fn on_command(
+            &mut self,
+            _inbound: &ServerToAgent,
+        ) -> Result<Option<AgentToServer>, ApiClientError> {
+            self.record("on_command")
+        }
+        fn on_agent_remote_config(
+            &mut self,
+            _inbound: &ServerToAgent,
+        ) -> Result<Option<AgentToServer>, ApiClientError> {
+            self.record("on_agent_remote_config")?;
+            if self.fail_remote_config {
+                return Err(ApiClientError::new(line!(), "bad config"));
+            }
+            Ok(None)
+        }
+        fn on_connection_settings_offers(
+            &mut self,
+            _inbound: &ServerToAgent,
+        ) -> Result<Option<AgentToServer>, ApiClientError> {
+            self.record("on_connection_settings_offers")
+        }
+        fn on_packages_available(
+            &mut self,
+            _inbound: &ServerToAgent,
+        ) -> Result<Option<AgentToServer>, ApiClientError> {
+            self.record("on_packages_available")
+        }
+        fn on_connect(&mut self, _reason: &str) {
+            let _ = self.record("on_connect");
+        }
+        fn on_disconnect(&mut self, reason: &DisconnectReason) {
+            let _ = self.record(match reason {
+                DisconnectReason::ConnectionLost(_) => "on_disconnect(lost)",
+                DisconnectReason::RetriesExhausted(_) => "on_disconnect(exhausted)",
+            });
+        }
+        fn on_reconnect(&mut self, _reason: &str) {
+            let _ = self.record("on_reconnect");
+        }
+        fn on_instance_uid_changed(&mut self, _previous: &InstanceUid, _current: &InstanceUid) {
+            let _ = self.record("on_instance_uid_changed");
+        }
+    }
+
+    impl ChildCallbacks for Recorder {
+        fn on_agent_remote_config(
+            &mut self,
+            _inbound: &ServerToAgent,
+        ) -> Result<Option<AgentToServer>, ApiClientError> {
+            self.record("child on_agent_remote_config")
+        }
+        fn on_command(
+            &mut self,
+            _inbound: &ServerToAgent,
+        ) -> Result<Option<AgentToServer>, ApiClientError> {
+            self.record("child on_command")
+        }
+        fn on_packages_available(
+            &mut self,
+            _inbound: &ServerToAgent,
+        ) -> Result<Option<AgentToServer>, ApiClientError> {
+            self.record("child on_packages_available")
+        }
+        fn on_instance_uid_changed(&mut self, _previous: &InstanceUid, _current: &InstanceUid) {
+            let _ = self.record("child on_instance_uid_changed");
+        }
+    }
+
+    /// A session past its handshake, without state directory or key store, calling `recorder`
+    fn session(recorder: &Recorder) -> Session<'static> {
+        session_in(recorder, None)
+    }
+
+    /// Settings for a local server, keeping the client state in `state_dir`
+    fn settings(state_dir: Option<PathBuf>) -> ConnectionSettings {
+        ConnectionSettings {
+            server_endpoint: "http://localhost:4320".to_string(),
+            api_key: "".to_string(),
+            listen_path: "/v1/opamp".to_string(),
+            name: "agent".to_string(),
+            version: "1.0.0".to_string(),
+            instance_id: "01HF4Z5J9Q3X7Y2M8K6N0P1R2S".to_string(),
+            debugmode: log::LevelFilter::Off,
+            key_store: None,
+            state_dir,
+        }
+    }
+
+    /// A session past its handshake keeping its state in `state_dir`, calling `recorder`
+    fn session_in(recorder: &Recorder, state_dir: Option<PathBuf>) -> Session<'static> {
+        let settings = settings(state_dir);
+        let mut session = Session::new(settings, Box::new(recorder.clone())).unwrap();
+        session.enqueue_handshake().unwrap();
+        session.outbox.clear();
+        session
+    }
+
+    #[test]
+    fn validate_header_accepts_tokens_and_visible_values() {
+        assert_eq!(
+            validate_header(&header("Authorization", "Bearer a.b-c\tx")).unwrap(),
+            ("Authorization".to_string(), "Bearer a.b-c\tx".to_string())
+        );
+        assert!(validate_header(&header("X-Api_Key~1", "")).is_ok());
+    }
+
+    #[test]
+    fn validate_header_rejects_invalid_names() {
+        for key in ["", "X Api", "X:Api", "Ünicode", "X\r\nInjected"] {
+            assert!(validate_header(&header(key, "value")).is_err(), "{:?}", key);
+        }
+    }
+
+    #[test]
+    fn validate_header_rejects_control_characters_in_values() {
+        for value in ["a\r\nX-Injected: 1", "a\nb", "a\0b", "a\u{7f}"] {
+            assert!(
+                validate_header(&header("X-Api", value)).is_err(),
+                "{:?}",
+                value
+            );
+        }
+    }
+
+    #[cfg(feature = "launcher")]
+    #[tokio::test]
+    async fn restart_reports_an_agent_exiting_on_startup_as_unhealthy() {
+        let recorder = Recorder {
+            capabilities: Capabilities::REPORTS_STATUS
+                | Capabilities::REPORTS_HEALTH
+                | Capabilities::ACCEPTS_RESTART_COMMAND,
+            ..Recorder::default()
+        };
+        let mut session = session(&recorder);
+        let task = Arc::new(Task::new("false".to_string(), vec![]));
+        assert!(task.launch());
+        session.set_restart_task(task);
+
+        session
+            .dispatch(&ServerToAgent {
+                instance_uid: session.instance_uid.to_wire(),
+                command: Some(ServerToAgentCommand {
+                    r#type: CommandType::Restart as i32,
+                }),
+                ..ServerToAgent::default()
+            })
+            .unwrap();
+        let restarting = session.outbox.pop().and_then(|m| m.health).unwrap();
+        assert_eq!(restarting.status, "restarting");
+        assert!(recorder.calls().is_empty());
+
+        // The restart runs in the background and does not hold up the poll loop
+        assert!(session.run_deferred().await);
+        assert!(session.outbox.is_empty());
+        while session.restart.as_ref().is_some_and(|r| !r.is_finished()) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(session.run_deferred().await);
+        let health = session.outbox.pop().and_then(|m| m.health).unwrap();
+        assert!(!health.healthy);
+        assert_eq!(health.status, "restart failed");
+        assert!(
+            health.last_error.contains("exited"),
+            "{}",
+            health.last_error
+        );
+    }
+
+    /// A message carrying every capability gated request to the supervisor
+    fn gated_message(session: &Session) -> ServerToAgent {
+        ServerToAgent {
+            instance_uid: session.instance_uid.to_wire(),
+            remote_config: Some(AgentRemoteConfig {
+                config_hash: vec![1],
+                ..AgentRemoteConfig::default()
+            }),
+            command: Some(ServerToAgentCommand {
+                r#type: CommandType::Restart as i32,
+            }),
+            packages_available: Some(PackagesAvailable::default()),
+            flags: ServerToAgentFlags::ReportAvailableComponents as u64,
+            ..ServerToAgent::default()
+        }
+    }
+
+    #[test]
+    fn dispatch_rejects_requests_for_undeclared_capabilities() {
+        let recorder = Recorder {
+            capabilities: Capabilities::REPORTS_STATUS,
+            ..Recorder::default()
+        };
+        let mut session = session(&recorder);
+
+        session.dispatch(&gated_message(&session)).unwrap();
+        assert!(recorder.calls().is_empty());
+        assert!(session.outbox.is_empty());
+    }
+
+    #[test]
+    fn dispatch_hands_declared_requests_to_the_callbacks() {
+        let recorder = Recorder {
+            capabilities: Capabilities::REPORTS_STATUS
+                | Capabilities::ACCEPTS_RESTART_COMMAND
+                | Capabilities::ACCEPTS_PACKAGES,
+            ..Recorder::default()
+        };
+        let mut session = session(&recorder);
+
+        session.dispatch(&gated_message(&session)).unwrap();
+        assert_eq!(recorder.calls(), ["on_command", "on_packages_available"]);
+    }
+
+    fn remote_config(session: &Session, hash: &[u8]) -> ServerToAgent {
+        ServerToAgent {
+            instance_uid: session.instance_uid.to_wire(),
+            remote_config: Some(AgentRemoteConfig {
+                config_hash: hash.to_vec(),
+                ..AgentRemoteConfig::default()
+            }),
+            ..ServerToAgent::default()
+        }
+    }
+
+    fn remote_config_status(session: &mut Session) -> (RemoteConfigStatuses, Vec<u8>, String) {
+        let status = session.outbox.remove(0).remote_config_status.unwrap();
+        (
+            status.status(),
+            status.last_remote_config_hash,
+            status.error_message,
+        )
+    }
+
+    fn remote_config_recorder(fail_remote_config: bool) -> Recorder {
+        Recorder {
+            capabilities: Capabilities::REPORTS_STATUS
+                | Capabilities::ACCEPTS_REMOTE_CONFIG
+                | Capabilities::REPORTS_REMOTE_CONFIG,
+            fail_remote_config,
+            ..Recorder::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn remote_config_is_reported_applying_before_it_is_delivered() {
+        let recorder = remote_config_recorder(false);
+        let mut session = session(&recorder);
+
+        session.dispatch(&remote_config(&session, b"v1")).unwrap();
+        assert!(recorder.calls().is_empty());
+        assert_eq!(
+            remote_config_status(&mut session),
+            (
+                RemoteConfigStatuses::Applying,
+                b"v1".to_vec(),
+                "".to_string()
+            )
+        );
+
+        assert!(session.run_deferred().await);
+        assert_eq!(recorder.calls(), ["on_agent_remote_config"]);
+        assert_eq!(
+            remote_config_status(&mut session),
+            (
+                RemoteConfigStatuses::Applied,
+                b"v1".to_vec(),
+                "".to_string()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn remote_config_already_applied_is_not_delivered_again() {
+        let recorder = remote_config_recorder(false);
+        let mut session = session(&recorder);
+        session.dispatch(&remote_config(&session, b"v1")).unwrap();
+        session.run_deferred().await;
+        session.outbox.clear();
+
+        session.dispatch(&remote_config(&session, b"v1")).unwrap();
+        assert!(session.outbox.is_empty());
+        assert!(!session.run_deferred().await);
+        assert_eq!(recorder.calls(), ["on_agent_remote_config"]);
+    }
+
+    #[tokio::test]
+    async fn remote_config_rejected_by_the_application_is_reported_failed() {
+        let recorder = remote_config_recorder(true);
+        let mut session = session(&recorder);
+        session.dispatch(&remote_config(&session, b"v2")).unwrap();
+        session.outbox.clear();
+
+        session.run_deferred().await;
+        let (status, hash, error) = remote_config_status(&mut session);
+        assert_eq!(
+            (status, hash),
+            (RemoteConfigStatuses::Failed, b"v2".to_vec())
+        );
+        assert!(error.contains("bad config"), "{}", error);
+    }
+
+    fn opamp_offer(session: &Session, endpoint: &str, heartbeat: u64) -> ServerToAgent {
+        ServerToAgent {
+            instance_uid: session.instance_uid.to_wire(),
+            connection_settings: Some(ConnectionSettingsOffers {
+                opamp: Some(OpAmpConnectionSettings {
+                    destination_endpoint: endpoint.to_string(),
+                    heartbeat_interval_seconds: heartbeat,
+                    ..OpAmpConnectionSettings::default()
+                }),
+                ..ConnectionSettingsOffers::default()
+            }),
+            ..ServerToAgent::default()
+        }
+    }
+
+    fn heartbeat_recorder() -> Recorder {
+        Recorder {
+            capabilities: Capabilities::REPORTS_STATUS
+                | Capabilities::ACCEPTS_OPAMP_CONNECTION_SETTINGS
+                | Capabilities::REPORTS_HEARTBEAT,
+            ..Recorder::default()
+        }
+    }
+
+    #[test]
+    fn rejected_opamp_offer_keeps_the_heartbeat_interval() {
+        let mut session = session(&heartbeat_recorder());
+
+        let offer = opamp_offer(&session, "ws://localhost:4320/v1/opamp", 5);
+        session.dispatch(&offer).unwrap();
+        assert_eq!(session.heartbeat_interval, DEFAULT_HEARTBEAT_INTERVAL);
+        assert!(!session.take_reconnect());
+    }
+
+    #[test]
+    fn expired_opamp_offer_restores_the_heartbeat_interval() {
+        let mut session = session(&heartbeat_recorder());
+        let previous = session.destination.clone();
+
+        let offer = opamp_offer(&session, "http://localhost:4321/v1/opamp", 5);
+        session.dispatch(&offer).unwrap();
+        assert_eq!(session.heartbeat_interval, Duration::from_secs(5));
+        assert!(session.take_reconnect());
+
+        if let Some((_, _, deadline)) = session.fallback.as_mut() {
+            *deadline = Instant::now();
+        }
+        session.restore_expired_destination();
+        assert_eq!(session.destination, previous);
+        assert_eq!(session.heartbeat_interval, DEFAULT_HEARTBEAT_INTERVAL);
+    }
+
+    #[test]
+    fn heartbeat_only_opamp_offer_applies_right_away() {
+        let mut session = session(&heartbeat_recorder());
+
+        let offer = opamp_offer(&session, "", 5);
+        session.dispatch(&offer).unwrap();
+        assert_eq!(session.heartbeat_interval, Duration::from_secs(5));
+        assert!(!session.take_reconnect());
+        assert!(session.fallback.is_none());
+    }
+
+    #[test]
+    fn sequence_num_continues_after_a_restart() {
+        let state_dir = std::env::temp_dir().join(format!("opamp-seqno-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&state_dir);
+        let recorder = Recorder {
+            capabilities: Capabilities::REPORTS_STATUS,
+            ..Recorder::default()
+        };
+
+        let mut session = session_in(&recorder, Some(state_dir.clone()));
+        for _ in 0..3 {
+            let mut message = session.heartbeat();
+            session.prepare(&mut message);
+        }
+        drop(session);
+
+        let mut session = session_in(&recorder, Some(state_dir.clone()));
+        let mut message = session.heartbeat();
+        session.prepare(&mut message);
+        assert_eq!(message.sequence_num, 4);
+        let _ = std::fs::remove_dir_all(&state_dir);
+    }
+
+    #[test]
+    fn backoff_grows_until_the_retries_are_exhausted() {
+        let mut session = session(&Recorder::default());
+        let delays: Vec<_> = (0..3)
+            .map(|_| session.backoff("refused").unwrap())
+            .collect();
+        assert_eq!(delays, [2, 4, 8].map(Duration::from_secs));
+
+        for _ in 3..10 {
+            session.backoff("refused").unwrap();
+        }
+        assert!(session.backoff("refused").is_err());
+        assert_eq!(session.backoff("refused").unwrap(), Duration::from_secs(2));
+    }
+
+    fn custom_message(capability: &str, r#type: &str) -> CustomMessage {
+        CustomMessage {
+            capability: capability.to_string(),
+            r#type: r#type.to_string(),
+            data: vec![],
+        }
+    }
+
+    fn custom_messages(session: &Session) -> Vec<String> {
+        session
+            .outbox
+            .iter()
+            .filter_map(|message| message.custom_message.as_ref())
+            .map(|message| message.r#type.clone())
+            .collect()
+    }
+
+    #[test]
+    fn send_custom_message_waits_for_the_pending_one() {
+        let mut session = session(&Recorder::default());
+        session
+            .register_custom_capability("io.opamp.echo", Box::new(Echo))
+            .unwrap();
+        session.outbox.clear();
+
+        assert!(session
+            .send_custom_message(custom_message("io.opamp.other", "ping"))
+            .is_err());
+        session
+            .send_custom_message(custom_message("io.opamp.echo", "ping"))
+            .unwrap();
+        assert!(session
+            .send_custom_message(custom_message("io.opamp.echo", "again"))
+            .is_err());
+        assert_eq!(custom_messages(&session), ["ping"]);
+
+        session.outbox.clear();
+        session
+            .send_custom_message(custom_message("io.opamp.echo", "again"))
+            .unwrap();
+        assert_eq!(custom_messages(&session), ["again"]);
+    }
+
+    #[test]
+    fn custom_message_reply_waits_for_the_pending_one() {
+        let recorder = Recorder::default();
+        let mut session = session(&recorder);
+        session
+            .register_custom_capability("io.opamp.echo", Box::new(Echo))
+            .unwrap();
+        session.outbox.clear();
+        let inbound = |session: &Session| ServerToAgent {
+            instance_uid: session.instance_uid.to_wire(),
+            custom_message: Some(custom_message("io.opamp.echo", "ping")),
+            ..ServerToAgent::default()
+        };
+
+        session.dispatch(&inbound(&session)).unwrap();
+        assert_eq!(custom_messages(&session), ["reply"]);
+
+        // The first reply has not been sent yet, so the second one is dropped
+        session.dispatch(&inbound(&session)).unwrap();
+        assert_eq!(custom_messages(&session), ["reply"]);
+
+        session.outbox.clear();
+        session.dispatch(&inbound(&session)).unwrap();
+        assert_eq!(custom_messages(&session), ["reply"]);
+    }
+
+    #[test]
+    fn transitions_are_published_and_reflected_in_the_status() {
+        let mut session = session(&Recorder::default());
+        let mut transitions = session.subscribe();
+
+        session.transition(State::Connecting(String::new()));
+        session.backoff("refused").unwrap();
+        session.transition(State::Disconnected("refused".to_string()));
+        // Staying in the same state is not a transition
+        session.transition(State::Disconnected("refused".to_string()));
+
+        let first = transitions.try_recv().unwrap();
+        assert!(matches!(
+            (first.from, first.to),
+            (State::Disconnected(_), State::Connecting(_))
+        ));
+        let second = transitions.try_recv().unwrap();
+        assert!(matches!(
+            (second.from, second.to),
+            (State::Connecting(_), State::Disconnected(_))
+        ));
+        assert!(transitions.try_recv().is_err());
+
+        let status = session.status();
+        assert!(matches!(status.state, State::Disconnected(_)));
+        assert_eq!(status.last_error.as_deref(), Some("refused"));
+        assert_eq!(status.errors, 1);
+        assert_eq!(status.retries, 1);
+
+        session.transition(State::Connecting(String::new()));
+        session.transition(State::Connected(String::new()));
+        assert_eq!(session.status().retries, 0);
+    }
+
+    #[test]
+    fn lifecycle_callbacks_follow_the_connection() {
+        let recorder = Recorder::default();
+        let mut session = session(&recorder);
+
+        session.transition(State::Connecting(String::new()));
+        session.transition(State::Connected(String::new()));
+        session.transition(State::Polling(String::new()));
+        session.transition(State::Disconnected("reset".to_string()));
+        session.transition(State::Connecting(String::new()));
+        session.transition(State::Connected(String::new()));
+        assert_eq!(
+            recorder.calls(),
+            ["on_connect", "on_disconnect(lost)", "on_reconnect"]
+        );
+
+        // A dropped connection is reported right away, failed attempts once the retries run out
+        session.transition(State::Connecting(String::new()));
+        session.transition(State::Disconnected(String::new()));
+        session.transition(State::Connecting(String::new()));
+        while session.backoff("refused").is_ok() {}
+        session.transition(State::Disconnected("refused".to_string()));
+        assert_eq!(
+            recorder.calls()[3..],
+            ["on_disconnect(lost)", "on_disconnect(exhausted)"]
+        );
+    }
+
+    #[test]
+    fn server_assigned_instance_uid_is_adopted() {
+        let recorder = Recorder::default();
+        let mut session = session(&recorder);
+        let assigned = InstanceUid::from([7; 16]);
+        let identify = |session: &Session, uid: Vec<u8>| ServerToAgent {
+            instance_uid: session.instance_uid.to_wire(),
+            agent_identification: Some(AgentIdentification {
+                new_instance_uid: uid,
+            }),
+            ..ServerToAgent::default()
+        };
+
+        session
+            .dispatch(&identify(&session, vec![1, 2, 3]))
+            .unwrap();
+        assert_ne!(session.instance_uid, assigned);
+        assert!(session.outbox.is_empty());
+
+        session
+            .dispatch(&identify(&session, assigned.to_wire()))
+            .unwrap();
+        assert_eq!(session.instance_uid, assigned);
+        assert_eq!(session.settings.instance_id, assigned.to_string());
+        assert_eq!(recorder.calls(), ["on_instance_uid_changed"]);
+
+        let update = session.outbox.pop().unwrap();
+        assert_eq!(update.instance_uid, assigned.to_wire());
+        let description = update.agent_description.unwrap();
+        assert!(description
+            .identifying_attributes
+            .contains(&attribute("service.instance.id", assigned.to_string())));
+        assert_eq!(
+            session.get_status().unwrap().instance_uid,
+            assigned.to_wire()
+        );
+
+        // Assigning the current identity again changes nothing
+        session
+            .dispatch(&identify(&session, assigned.to_wire()))
+            .unwrap();
+        assert!(session.outbox.is_empty());
+        assert_eq!(recorder.calls().len(), 1);
+    }
+
+    #[test]
+    fn component_health_is_kept_across_health_updates() {
+        let recorder = Recorder {
+            capabilities: Capabilities::REPORTS_STATUS | Capabilities::REPORTS_HEALTH,
+            ..Recorder::default()
+        };
+        let mut session = session(&recorder);
+        let start = session
+            .get_status()
+            .unwrap()
+            .health
+            .unwrap()
+            .start_time_unix_nano;
+        let receiver = ComponentHealth {
+            healthy: false,
+            last_error: "port in use".to_string(),
+            ..ComponentHealth::default()
+        };
+
+        session
+            .set_component_health("receiver/otlp", Some(receiver))
+            .unwrap();
+        session
+            .set_health(ComponentHealth {
+                healthy: true,
+                status: "degraded".to_string(),
+                ..ComponentHealth::default()
+            })
+            .unwrap();
+        assert_eq!(session.outbox.len(), 2);
+
+        let health = session.outbox.pop().unwrap().health.unwrap();
+        assert_eq!(health.status, "degraded");
+        assert_eq!(health.start_time_unix_nano, start);
+        assert_ne!(health.status_time_unix_nano, 0);
+        let component = &health.component_health_map["receiver/otlp"];
+        assert_eq!(component.last_error, "port in use");
+        assert_ne!(component.status_time_unix_nano, 0);
+
+        session.set_component_health("receiver/otlp", None).unwrap();
+        let health = session.outbox.pop().unwrap().health.unwrap();
+        assert!(health.component_health_map.is_empty());
+        assert_eq!(health.status, "degraded");
+    }
+
+    #[test]
+    fn health_is_not_sent_without_the_capability() {
+        let mut session = session(&Recorder::default());
+        session
+            .set_component_health("receiver/otlp", Some(ComponentHealth::default()))
+            .unwrap();
+        assert!(session.outbox.is_empty());
+        let health = session.get_status().unwrap().health.unwrap();
+        assert!(health.component_health_map.contains_key("receiver/otlp"));
+    }
+
+    #[test]
+    fn messages_are_routed_by_instance_uid() {
+        let recorder = Recorder::default();
+        let mut session = session(&recorder);
+        let child = InstanceUid::from([9; 16]);
+        session
+            .register_child(ChildAgent {
+                instance_uid: child,
+                capabilities: Capabilities::REPORTS_STATUS | Capabilities::ACCEPTS_REMOTE_CONFIG,
+                description: AgentDescription::default(),
+                effective_config: None,
+                handler: Box::new(recorder.clone()),
+            })
+            .unwrap();
+        assert_eq!(session.outbox.pop().unwrap().instance_uid, child.to_wire());
+
+        // The child did not declare AcceptsRestartCommand, so only the config reaches it
+        session
+            .dispatch(&ServerToAgent {
+                instance_uid: child.to_wire(),
+                remote_config: Some(AgentRemoteConfig {
+                    config: None,
+                    config_hash: vec![1],
+                }),
+                command: Some(ServerToAgentCommand::default()),
+                ..ServerToAgent::default()
+            })
+            .unwrap();
+        assert_eq!(recorder.calls(), ["child on_agent_remote_config"]);
+        let status = session.outbox.pop().unwrap();
+        assert_eq!(status.instance_uid, child.to_wire());
+        assert_eq!(
+            status.remote_config_status.unwrap().status,
+            RemoteConfigStatuses::Applied as i32
+        );
+
+        session
+            .dispatch(&ServerToAgent {
+                instance_uid: InstanceUid::from([5; 16]).to_wire(),
+                ..ServerToAgent::default()
+            })
+            .unwrap();
+        assert_eq!(recorder.calls()[1..], ["on_health_check"]);
+
+        let assigned = InstanceUid::from([8; 16]);
+        session
+            .dispatch(&ServerToAgent {
+                instance_uid: child.to_wire(),
+                agent_identification: Some(AgentIdentification {
+                    new_instance_uid: assigned.to_wire(),
+                }),
+                ..ServerToAgent::default()
+            })
+            .unwrap();
+        assert_eq!(recorder.calls()[2..], ["child on_instance_uid_changed"]);
+        assert_ne!(session.instance_uid, assigned);
+        assert!(session.deregister_child(&child).is_err());
+
+        session.deregister_child(&assigned).unwrap();
+        let disconnect = session.outbox.pop().unwrap();
+        assert_eq!(disconnect.instance_uid, assigned.to_wire());
+        assert!(disconnect.agent_disconnect.is_some());
+    }
+
+    #[test]
+    fn children_can_not_share_the_supervisor_instance_uid() {
+        let recorder = Recorder::default();
+        let mut session = session(&recorder);
+        let child = ChildAgent {
+            instance_uid: session.instance_uid,
+            capabilities: Capabilities::REPORTS_STATUS,
+            description: AgentDescription::default(),
+            effective_config: None,
+            handler: Box::new(recorder.clone()),
+        };
+        assert!(session.register_child(child).is_err());
+        assert!(session.outbox.is_empty());
+    }
+
+    #[test]
+    fn server_assigned_instance_uid_survives_a_restart() {
+        let state_dir = std::env::temp_dir().join(format!("opamp-identity-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&state_dir);
+        let recorder = Recorder::default();
+        let assigned = InstanceUid::from([7; 16]);
+
+        let mut session = session_in(&recorder, Some(state_dir.clone()));
+        let configured = session.instance_uid;
+        assert_eq!(configured.to_string(), "01HF4Z5J9Q3X7Y2M8K6N0P1R2S");
+        session
+            .dispatch(&ServerToAgent {
+                instance_uid: configured.to_wire(),
+                agent_identification: Some(AgentIdentification {
+                    new_instance_uid: assigned.to_wire(),
+                }),
+                ..ServerToAgent::default()
+            })
+            .unwrap();
+        // The state directory belongs to one agent at a time
+        let second = Session::new(
+            settings(Some(state_dir.clone())),
+            Box::new(recorder.clone()),
+        );
+        assert!(second.is_err());
+        drop(session);
+
+        let session = session_in(&recorder, Some(state_dir.clone()));
+        assert_eq!(session.instance_uid, assigned);
+        assert_eq!(session.settings.instance_id, assigned.to_string());
+        let _ = std::fs::remove_dir_all(&state_dir);
+    }
+
+    #[cfg(feature = "csr")]
+    #[test]
+    fn signed_certificate_is_paired_with_the_requested_key() {
+        let mut undeclared = session(&Recorder {
+            capabilities: Capabilities::REPORTS_STATUS,
+            ..Recorder::default()
+        });
+        assert!(undeclared.request_certificate().is_err());
+
+        let mut session = session(&Recorder {
+            capabilities: Capabilities::REPORTS_STATUS
+                | Capabilities::ACCEPTS_OPAMP_CONNECTION_SETTINGS,
+            ..Recorder::default()
+        });
+        session.request_certificate().unwrap();
+        let request = session.outbox.pop().unwrap().connection_settings_request;
+        assert!(request
+            .and_then(|request| request.opamp)
+            .and_then(|opamp| opamp.certificate_request)
+            .is_some());
+        let private_key = session.certificate_key.clone().unwrap();
+
+        // The server sends back the signed certificate without a private key
+        let mut offer = opamp_offer(&session, "", 0);
+        let settings = offer.connection_settings.as_mut().unwrap();
+        settings.opamp.as_mut().unwrap().certificate = Some(TlsCertificate {
+            public_key: tls::tests::sign(&private_key),
+            ..TlsCertificate::default()
+        });
+        session.dispatch(&offer).unwrap();
+        assert!(session.take_reconnect());
+        let certificate = session.destination.certificate.clone().unwrap();
+        assert_eq!(certificate.private_key, private_key);
+
+        session.record_exchange();
+        assert!(session.certificate_key.is_none());
+    }
+}
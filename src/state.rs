@@ -11,6 +11,59 @@ pub enum State {
     Waiting(String),
 }
 
+/// A single change of FSM state as delivered to subscribers of [`crate::api::Api::subscribe`]
+#[derive(Clone, Debug)]
+pub struct StateTransition {
+    pub from: State,
+    pub to: State,
+    pub timestamp: std::time::SystemTime,
+}
+
+/// Reason reported to `ApiCallbacks::on_disconnect`
+#[derive(Clone, Debug)]
+pub enum DisconnectReason {
+    /// An established connection to the server failed
+    ConnectionLost(String),
+    /// Connecting failed `OPAMP_CONNECT_RETRIES` times in a row. The client keeps retrying
+    /// with a fresh back-off cycle
+    RetriesExhausted(String),
+}
+
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DisconnectReason::ConnectionLost(reason) => write!(f, "connection lost: {}", reason),
+            DisconnectReason::RetriesExhausted(reason) => {
+                write!(f, "retries exhausted: {}", reason)
+            }
+        }
+    }
+}
+
+/// A snapshot of the client connection as reported by [`crate::api::Api::status`]
+///
+/// Properties:
+///
+/// * `state`: The current FSM state.
+/// * `last_error`: The most recent connection or transport error, if any.
+/// * `last_exchange`: Time of the last successful exchange with the server.
+/// * `sequence_num`: Sequence number of the last message sent to the server.
+/// * `retries`: Number of consecutive failed connection attempts.
+/// * `messages_sent`: Number of messages delivered to the server.
+/// * `messages_received`: Number of messages received from the server.
+/// * `errors`: Number of connection, transport and server errors.
+#[derive(Clone, Debug)]
+pub struct ConnectionStatus {
+    pub state: State,
+    pub last_error: Option<String>,
+    pub last_exchange: Option<std::time::SystemTime>,
+    pub sequence_num: u64,
+    pub retries: u32,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub errors: u64,
+}
+
 pub enum StateResponse {
     Reply(String),
     Error(String),
@@ -32,7 +85,15 @@ macro_rules! state_log {
 }
 
 impl State {
-    pub async fn evaluate(self, client: &mut dyn Channel) -> Result<State, ApiClientError> {
+    /// Whether the state implies an established connection to the server
+    pub fn is_connected(&self) -> bool {
+        matches!(
+            self,
+            State::Connected(_) | State::Polling(_) | State::Sending(_) | State::Waiting(_)
+        )
+    }
+
+    pub async fn evaluate(self, client: &mut dyn Channel<'_>) -> Result<State, ApiClientError> {
         log::trace!("In state {:?}", self);
         match self {
             State::Disconnected(_) => Ok(State::Connecting(nullstr!())),
@@ -0,0 +1,286 @@
+use crate::api::ApiClientError;
+use crate::opamp::InstanceUid;
+use fs2::FileExt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Name of the file in the state directory holding the instance id
+const INSTANCE_ID: &str = "instance_id";
+
+/// Name of the lock file held while an agent uses the state directory
+const LOCK: &str = "instance.lock";
+
+/// A `StateStore` keeps the state the agent reported to the server across restarts, so that the
+/// first status report after a restart reflects what is actually applied. Values are opaque
+/// byte strings saved under a key.
+pub trait StateStore: Send + Sync {
+    /// Reads the value saved under `key`, `None` if nothing was saved yet
+    fn load(&self, key: &str) -> Result<Option<Vec<u8>>, ApiClientError>;
+    /// Saves `value` under `key`. A crash while saving must leave the previous value intact.
+    fn save(&mut self, key: &str, value: &[u8]) -> Result<(), ApiClientError>;
+}
+
+/// The `FileStateStore` struct is the default `StateStore`. Each key is kept in its own
+/// `<key>.state` file in a directory and replaced atomically when saved.
+///
+/// Properties:
+///
+/// * `dir`: The directory holding the state files.
+pub struct FileStateStore {
+    dir: PathBuf,
+}
+
+impl FileStateStore {
+    pub fn new(dir: impl Into<PathBuf>) -> FileStateStore {
+        FileStateStore { dir: dir.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.state", key))
+    }
+}
+
+impl StateStore for FileStateStore {
+    fn load(&self, key: &str) -> Result<Option<Vec<u8>>, ApiClientError> {
+        let path = self.path(key);
+        match fs::read(&path) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(store_error(line!(), &path, e)),
+        }
+    }
+
+    fn save(&mut self, key: &str, value: &[u8]) -> Result<(), ApiClientError> {
+        fs::create_dir_all(&self.dir).map_err(|e| store_error(line!(), &self.dir, e))?;
+        write_atomic(&self.path(key), value, None)
+    }
+}
+
+/// The `Identity` struct keeps the instance id of the agent in a state directory so it survives
+/// restarts. The directory is locked for as long as the `Identity` exists, so two agents can not
+/// run with the same identity.
+///
+/// Properties:
+///
+/// * `path`: The file holding the instance id.
+/// * `_lock`: The locked lock file. The lock is released when it is dropped.
+pub(crate) struct Identity {
+    path: PathBuf,
+    _lock: File,
+}
+
+impl Identity {
+    /// Locks `state_dir` and reads the instance id saved there. When there is none yet,
+    /// `instance_id` is saved and used from then on.
+    ///
+    /// Returns:
+    ///
+    /// The locked identity and the instance id to connect with
+    pub(crate) fn open(
+        state_dir: &Path,
+        instance_id: &InstanceUid,
+    ) -> Result<(Identity, InstanceUid), ApiClientError> {
+        fs::create_dir_all(state_dir).map_err(|e| store_error(line!(), state_dir, e))?;
+
+        let lock_path = state_dir.join(LOCK);
+        let lock = File::create(&lock_path).map_err(|e| store_error(line!(), &lock_path, e))?;
+        lock.try_lock_exclusive().map_err(|_| {
+            ApiClientError::new(
+                line!(),
+                format!(
+                    "State directory {} is in use by another agent",
+                    state_dir.display()
+                )
+                .as_str(),
+            )
+        })?;
+
+        let identity = Identity {
+            path: state_dir.join(INSTANCE_ID),
+            _lock: lock,
+        };
+        let saved = match fs::read_to_string(&identity.path) {
+            Ok(text) => match text.trim().parse::<InstanceUid>() {
+                Ok(uid) => Some(uid),
+                Err(e) => {
+                    log::warn!("Replacing unreadable {}: {}", identity.path.display(), e);
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+
+        match saved {
+            Some(uid) => {
+                log::info!("Using saved instance id {}", uid);
+                Ok((identity, uid))
+            }
+            None => {
+                identity.save(instance_id)?;
+                Ok((identity, *instance_id))
+            }
+        }
+    }
+
+    /// Saves a new instance id, e.g. one assigned by the server
+    pub(crate) fn save(&self, instance_id: &InstanceUid) -> Result<(), ApiClientError> {
+        write_atomic(&self.path, format!("{}\n", instance_id).as_bytes(), None)
+    }
+}
+
+/// Replaces the contents of `path` atomically, so a crash never leaves a partially written file.
+/// The contents are staged in `<file name>.tmp` next to it. On Unix the new file is created with
+/// `mode`, e.g. `0o600` for private keys, when given, and the rename is synced to disk.
+pub(crate) fn write_atomic(
+    path: &Path,
+    contents: &[u8],
+    mode: Option<u32>,
+) -> Result<(), ApiClientError> {
+    let mut staging = path
+        .file_name()
+        .ok_or_else(|| {
+            ApiClientError::new(
+                line!(),
+                format!("{}: not a file name", path.display()).as_str(),
+            )
+        })?
+        .to_os_string();
+    staging.push(".tmp");
+    let staging = path.with_file_name(staging);
+
+    // A file left behind by an interrupted write would keep its permissions
+    match fs::remove_file(&staging) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(store_error(line!(), &staging, e))
+        }
+        _ => {}
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+
+    let mut file = options
+        .open(&staging)
+        .map_err(|e| store_error(line!(), &staging, e))?;
+    file.write_all(contents)
+        .and_then(|_| file.sync_all())
+        .map_err(|e| store_error(line!(), &staging, e))?;
+    fs::rename(&staging, path).map_err(|e| store_error(line!(), path, e))?;
+
+    // The rename itself is only durable once the directory is synced
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .map_err(|e| store_error(line!(), dir, e))?;
+    }
+    Ok(())
+}
+
+pub(crate) fn store_error(code: u32, path: &Path, e: std::io::Error) -> ApiClientError {
+    ApiClientError::new(code, format!("{}: {}", path.display(), e).as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of its own for each test, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir =
+                std::env::temp_dir().join(format!("opamp-store-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn write_atomic_replaces_the_contents() {
+        let dir = TempDir::new("replace");
+        let path = dir.0.join("remote_config_status.state");
+        write_atomic(&path, b"first", None).unwrap();
+        write_atomic(&path, b"second", None).unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn write_atomic_stages_next_to_the_full_file_name() {
+        let dir = TempDir::new("staging");
+        let path = dir.0.join("opamp.pem");
+        let other = dir.0.join("opamp.key");
+        // A stale staging file of a sibling must survive, one of our own is replaced
+        fs::write(dir.0.join("opamp.tmp"), b"sibling").unwrap();
+        fs::write(dir.0.join("opamp.pem.tmp"), b"stale").unwrap();
+
+        write_atomic(&path, b"certificate", None).unwrap();
+        write_atomic(&other, b"key", None).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"certificate");
+        assert_eq!(fs::read(&other).unwrap(), b"key");
+        assert_eq!(fs::read(dir.0.join("opamp.tmp")).unwrap(), b"sibling");
+        assert!(!dir.0.join("opamp.pem.tmp").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_atomic_applies_the_mode_despite_a_stale_staging_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("mode");
+        let path = dir.0.join("opamp.key");
+        let staging = dir.0.join("opamp.key.tmp");
+        fs::write(&staging, b"stale").unwrap();
+        fs::set_permissions(&staging, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_atomic(&path, b"private", Some(0o600)).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn file_state_store_keeps_values_by_key() {
+        let dir = TempDir::new("state");
+        let mut store = FileStateStore::new(dir.0.join("state"));
+        assert!(store.load("effective_config").unwrap().is_none());
+
+        store.save("effective_config", b"config").unwrap();
+        store.save("sequence_num", b"seqno").unwrap();
+        let reopened = FileStateStore::new(dir.0.join("state"));
+        assert_eq!(
+            reopened.load("effective_config").unwrap().unwrap(),
+            b"config"
+        );
+        assert_eq!(reopened.load("sequence_num").unwrap().unwrap(), b"seqno");
+    }
+
+    #[test]
+    fn identity_locks_the_state_directory() {
+        let dir = TempDir::new("identity");
+        let uid = InstanceUid::generate();
+        let (identity, saved) = Identity::open(&dir.0, &uid).unwrap();
+        assert_eq!(saved, uid);
+        assert!(Identity::open(&dir.0, &InstanceUid::generate()).is_err());
+
+        drop(identity);
+        let (_identity, saved) = Identity::open(&dir.0, &InstanceUid::generate()).unwrap();
+        assert_eq!(saved, uid);
+    }
+}